@@ -0,0 +1,95 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kvs::{
+    Frontend, KvStore, KvsClient, KvsServer, NaiveThreadPool, RayonThreadPool,
+    SharedQueueThreadPool, ThreadPool,
+};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Number of clients sending requests at the same time
+const CLIENTS: usize = 8;
+/// Number of requests each client sends per iteration
+const REQUESTS_PER_CLIENT: usize = 25;
+/// Pool sizes to compare
+const POOL_THREADS: [u32; 3] = [1, 4, 8];
+
+// Starts a server on a `KvStore` in a fresh directory. It runs until the benchmark exits.
+fn start_server<P: ThreadPool>(threads: u32) -> SocketAddr {
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    thread::spawn(move || {
+        let _temp_dir = temp_dir;
+        let pool = P::new(threads).unwrap();
+        KvsServer::new(engine, pool, Frontend::Kvs)
+            .run(addr)
+            .unwrap();
+    });
+    while TcpStream::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    addr
+}
+
+// Runs `request` for every key of every client, with all clients in parallel.
+fn run_clients(addr: SocketAddr, request: fn(&mut KvsClient, String)) {
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|client| {
+            thread::spawn(move || {
+                let mut kvs_client = KvsClient::connect(addr).unwrap();
+                for i in 0..REQUESTS_PER_CLIENT {
+                    request(
+                        &mut kvs_client,
+                        format!("key{}", client * REQUESTS_PER_CLIENT + i),
+                    );
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+}
+
+fn set(client: &mut KvsClient, key: String) {
+    client.set(key, "value".to_owned()).unwrap();
+}
+
+fn get(client: &mut KvsClient, key: String) {
+    assert_eq!(client.get(key).unwrap().as_deref(), Some("value"));
+}
+
+fn bench_pool<P: ThreadPool>(c: &mut Criterion, name: &str) {
+    let mut group = c.benchmark_group(name);
+    for threads in POOL_THREADS {
+        let addr = start_server::<P>(threads);
+        group.bench_with_input(BenchmarkId::new("write", threads), &addr, |b, &addr| {
+            b.iter(|| run_clients(addr, set))
+        });
+        // The keys written above are all there to read back.
+        group.bench_with_input(BenchmarkId::new("read", threads), &addr, |b, &addr| {
+            b.iter(|| run_clients(addr, get))
+        });
+    }
+    group.finish();
+}
+
+fn naive(c: &mut Criterion) {
+    bench_pool::<NaiveThreadPool>(c, "naive");
+}
+
+fn shared_queue(c: &mut Criterion) {
+    bench_pool::<SharedQueueThreadPool>(c, "shared_queue");
+}
+
+fn rayon(c: &mut Criterion) {
+    bench_pool::<RayonThreadPool>(c, "rayon");
+}
+
+criterion_group!(benches, naive, shared_queue, rayon);
+criterion_main!(benches);
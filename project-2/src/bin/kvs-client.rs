@@ -0,0 +1,52 @@
+use clap::{Parser, Subcommand};
+use kvs::{KvsClient, Result};
+use std::net::SocketAddr;
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Cli {
+    /// Address of the server, as IP:PORT
+    #[arg(long, default_value = "127.0.0.1:4000", global = true)]
+    addr: SocketAddr,
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Commands {
+    Set { key: String, value: String },
+    Get { key: String },
+    Rm { key: String },
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    match &cli.command {
+        Some(Commands::Set { key, value }) => {
+            let mut client = KvsClient::connect(cli.addr)?;
+            client.set(key.clone(), value.clone())
+        }
+        Some(Commands::Get { key }) => {
+            let mut client = KvsClient::connect(cli.addr)?;
+            match client.get(key.clone())? {
+                Some(value) => println!("{}", value),
+                None => println!("Key not found"),
+            }
+            Ok(())
+        }
+        Some(Commands::Rm { key }) => {
+            let mut client = KvsClient::connect(cli.addr)?;
+            match client.remove(key.clone()) {
+                Ok(_) => Ok(()),
+                Err(e) => {
+                    println!("{}", e);
+                    Err(e)
+                }
+            }
+        }
+        None => {
+            std::process::exit(1);
+        }
+    }
+}
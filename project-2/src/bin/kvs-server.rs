@@ -0,0 +1,120 @@
+use clap::{Parser, ValueEnum};
+use env_logger::Env;
+use kvs::{
+    Frontend, KvStore, KvsEngine, KvsServer, MemoryStore, NaiveThreadPool, RayonThreadPool, Result,
+    SharedQueueThreadPool, ThreadPool,
+};
+use log::info;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Cli {
+    /// Address to listen on, as IP:PORT
+    #[arg(long, default_value = "127.0.0.1:4000")]
+    addr: SocketAddr,
+    /// Storage backend to use
+    #[arg(long, value_enum, default_value_t = Engine::Kvs)]
+    engine: Engine,
+    /// Protocol to speak to clients
+    #[arg(long, value_enum, default_value_t = Protocol::Kvs)]
+    protocol: Protocol,
+    /// Also serve the Redis protocol on this address, as IP:PORT
+    #[arg(long)]
+    resp_addr: Option<SocketAddr>,
+    /// Also serve the HTTP API on this address, as IP:PORT
+    #[arg(long)]
+    http_addr: Option<SocketAddr>,
+    /// Thread pool that runs the requests
+    #[arg(long, value_enum, default_value_t = Pool::SharedQueue)]
+    pool: Pool,
+    /// Number of threads in the pool [default: number of CPUs]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    threads: Option<u32>,
+    /// Compact the store once no request has come in for this many seconds
+    #[arg(long, value_name = "SECONDS")]
+    compact_when_idle: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Engine {
+    Kvs,
+    Memory,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Protocol {
+    /// The native kvs protocol, used by `kvs-client`
+    Kvs,
+    /// The Redis protocol, RESP2 or RESP3
+    Resp,
+    /// A JSON REST API over HTTP
+    Http,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Pool {
+    /// A new thread for every request
+    Naive,
+    /// A fixed set of threads sharing one job queue
+    SharedQueue,
+    /// A work-stealing pool
+    Rayon,
+}
+
+fn main() -> Result<()> {
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    let cli = Cli::parse();
+    let dir = std::env::current_dir()?;
+    let threads = match cli.threads {
+        Some(threads) => threads,
+        None => std::thread::available_parallelism()?.get() as u32,
+    };
+
+    info!(
+        version = env!("CARGO_PKG_VERSION"),
+        engine:? = cli.engine,
+        protocol:? = cli.protocol,
+        pool:? = cli.pool,
+        threads = threads,
+        addr:% = cli.addr,
+        resp_addr:? = cli.resp_addr,
+        http_addr:? = cli.http_addr,
+        compact_when_idle:? = cli.compact_when_idle,
+        dir:? = dir;
+        "Starting kvs-server"
+    );
+
+    match cli.engine {
+        Engine::Kvs => with_pool(KvStore::open(&dir)?, threads, &cli),
+        Engine::Memory => with_pool(MemoryStore::open(&dir)?, threads, &cli),
+    }
+}
+
+fn with_pool<E: KvsEngine + Send + 'static>(engine: E, threads: u32, cli: &Cli) -> Result<()> {
+    match cli.pool {
+        Pool::Naive => run(engine, NaiveThreadPool::new(threads)?, cli),
+        Pool::SharedQueue => run(engine, SharedQueueThreadPool::new(threads)?, cli),
+        Pool::Rayon => run(engine, RayonThreadPool::new(threads)?, cli),
+    }
+}
+
+fn run<E: KvsEngine + Send + 'static, P: ThreadPool>(engine: E, pool: P, cli: &Cli) -> Result<()> {
+    let frontend = match cli.protocol {
+        Protocol::Kvs => Frontend::Kvs,
+        Protocol::Resp => Frontend::Resp,
+        Protocol::Http => Frontend::Http,
+    };
+    let mut server = KvsServer::new(engine, pool, frontend);
+    if let Some(addr) = cli.resp_addr {
+        server = server.listen(Frontend::Resp, addr);
+    }
+    if let Some(addr) = cli.http_addr {
+        server = server.listen(Frontend::Http, addr);
+    }
+    if let Some(idle) = cli.compact_when_idle {
+        server = server.compact_when_idle(Duration::from_secs(idle));
+    }
+    server.run(cli.addr)
+}
@@ -1,49 +1,174 @@
-use clap::{Parser, Subcommand};
-use kvs::{KvStore, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use kvs::{CustomError, KvStore, KvsEngine, MemoryStore, Result};
+use std::ops::Bound;
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct Cli {
+    /// Storage backend to use
+    #[arg(long, value_enum, default_value_t = Engine::Kvs, global = true)]
+    engine: Engine,
+    /// Open the store without writing to it, e.g. on a read-only mount; `get` always does
+    #[arg(long, global = true)]
+    read_only: bool,
     #[command(subcommand)]
     command: Option<Commands>,
 }
 
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Engine {
+    Kvs,
+    Memory,
+}
+
 #[derive(Debug, Subcommand)]
 enum Commands {
-    Set { key: String, value: String },
-    Get { key: String },
-    Rm { key: String },
+    Set {
+        key: String,
+        value: String,
+    },
+    Get {
+        key: String,
+    },
+    Rm {
+        key: String,
+    },
+    /// Print the key/value pairs in a range of keys, in key order, one tab-separated pair per line
+    Scan {
+        /// Only the keys that start with this prefix
+        #[arg(long, conflicts_with_all = ["from", "to"])]
+        prefix: Option<String>,
+        /// The first key of the range
+        #[arg(long)]
+        from: Option<String>,
+        /// The end of the range, which is left out
+        #[arg(long)]
+        to: Option<String>,
+        /// Print the pairs in descending key order
+        #[arg(long)]
+        reverse: bool,
+        /// Print at most this many pairs
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Reclaim the space taken by overwritten and removed values
+    Compact,
+    /// Migrate the store to the current on-disk format
+    Upgrade {
+        /// Write the migrated store to this directory instead of replacing the current one
+        #[arg(long)]
+        into: Option<PathBuf>,
+    },
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
+    // Only `get` and `scan` leave the store as it is
+    let is_read = matches!(
+        cli.command,
+        Some(Commands::Get { .. } | Commands::Scan { .. })
+    );
+    if cli.read_only && !is_read {
+        println!("{}", CustomError::ReadOnly);
+        return Err(CustomError::ReadOnly);
+    }
+
     match &cli.command {
-        Some(Commands::Set { key, value }) => {
-            let mut storage = KvStore::open(std::env::current_dir()?)?;
+        Some(Commands::Upgrade { into }) => upgrade(cli.engine, into.as_ref()),
+        Some(command) => match cli.engine {
+            Engine::Kvs if is_read => {
+                run(KvStore::open_read_only(std::env::current_dir()?)?, command)
+            }
+            Engine::Kvs => run(KvStore::open(std::env::current_dir()?)?, command),
+            Engine::Memory => run(MemoryStore::open(std::env::current_dir()?)?, command),
+        },
+        None => {
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Upgrades work on the files, so they never open the store
+fn upgrade(engine: Engine, into: Option<&PathBuf>) -> Result<()> {
+    if let Engine::Memory = engine {
+        println!("The memory engine has no on-disk format to upgrade");
+        std::process::exit(1);
+    }
+    let current_dir = std::env::current_dir()?;
+    let format_version = match into {
+        Some(into) => KvStore::upgrade_into(&current_dir, into)?,
+        None => KvStore::upgrade(&current_dir)?,
+    };
+    if format_version == KvStore::FORMAT_VERSION && into.is_none() {
+        println!("Already at format version {}", KvStore::FORMAT_VERSION);
+    } else {
+        println!(
+            "Upgraded from format version {} to {}",
+            format_version,
+            KvStore::FORMAT_VERSION
+        );
+    }
+    Ok(())
+}
+
+fn run<E: KvsEngine>(storage: E, command: &Commands) -> Result<()> {
+    match command {
+        Commands::Set { key, value } => {
             storage.set(key.clone(), value.clone())?;
-            Ok(())
+            storage.flush()
         }
-        Some(Commands::Get { key }) => {
-            let storage = KvStore::open(".")?;
+        Commands::Get { key } => {
             match storage.get(key.clone())? {
                 Some(value) => println!("{}", value),
                 None => println!("Key not found"),
             }
             Ok(())
         }
-        Some(Commands::Rm { key }) => {
-            let mut storage = KvStore::open(".")?;
-            match storage.remove(key.clone()) {
-                Ok(_) => Ok(()),
-                Err(e) => {
-                    println!("{}", e);
-                    Err(e)
-                }
+        Commands::Scan {
+            prefix,
+            from,
+            to,
+            reverse,
+            limit,
+        } => {
+            let scan = match prefix {
+                Some(prefix) => storage.scan_prefix(prefix),
+                None => storage.scan((
+                    from.clone().map_or(Bound::Unbounded, Bound::Included),
+                    to.clone().map_or(Bound::Unbounded, Bound::Excluded),
+                )),
+            };
+            let limit = limit.unwrap_or(usize::MAX);
+            match reverse {
+                true => print_pairs(scan.rev().take(limit)),
+                false => print_pairs(scan.take(limit)),
             }
         }
-        None => {
-            std::process::exit(1);
+        Commands::Rm { key } => match storage.remove(key.clone()) {
+            Ok(_) => storage.flush(),
+            Err(e) => {
+                println!("{}", e);
+                Err(e)
+            }
+        },
+        Commands::Compact => {
+            let report = storage.compact()?;
+            println!(
+                "Compacted {} files, reclaimed {} bytes in {:?}",
+                report.files_rewritten, report.bytes_reclaimed, report.duration
+            );
+            Ok(())
         }
+        Commands::Upgrade { .. } => unreachable!("upgrades don't open the store"),
+    }
+}
+
+fn print_pairs(pairs: impl Iterator<Item = Result<(String, String)>>) -> Result<()> {
+    for pair in pairs {
+        let (key, value) = pair?;
+        println!("{}\t{}", key, value);
     }
+    Ok(())
 }
@@ -0,0 +1,73 @@
+use crate::error::{CustomError, Result};
+use crate::protocol::{self, Request, RequestFrame, Response, ResponseFrame};
+use std::io::{BufReader, BufWriter};
+use std::net::{TcpStream, ToSocketAddrs};
+
+/// A client talking to a remote `kvs-server`.
+/// Errors reported by the server come back as the matching `CustomError` variant.
+/// # Examples
+/// ```
+/// use kvs::KvsClient;
+/// let mut client = KvsClient::connect("127.0.0.1:4000")?;
+/// client.set("key".to_string(), "value".to_string())?;
+/// ```
+pub struct KvsClient {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    /// The id of the next request
+    next_id: u64,
+}
+
+impl KvsClient {
+    /// Connect to a server at the given address.
+    /// Fails with `CustomError::ProtocolVersion` if the server speaks another protocol version.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<KvsClient> {
+        let mut tcp = TcpStream::connect(addr)?;
+        protocol::client_handshake(&mut tcp)?;
+        let tcp_writer = tcp.try_clone()?;
+        Ok(KvsClient {
+            reader: BufReader::new(tcp),
+            writer: BufWriter::new(tcp_writer),
+            next_id: 0,
+        })
+    }
+
+    /// Set a key to a value on the server
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        match self.send(Request::Set { key, value })? {
+            None => Ok(()),
+            Some(_) => Err(CustomError::UnexpectedResponse),
+        }
+    }
+
+    /// Get the value associated with a key from the server.
+    /// Returns `None` if the key does not exist.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.send(Request::Get { key })
+    }
+
+    /// Remove a key from the server.
+    /// Returns `CustomError::KeyNotFound` if the key does not exist.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        match self.send(Request::Remove { key })? {
+            None => Ok(()),
+            Some(_) => Err(CustomError::UnexpectedResponse),
+        }
+    }
+
+    /// Send one request and wait for its response
+    fn send(&mut self, request: Request) -> Result<Option<String>> {
+        let id = self.next_id;
+        self.next_id += 1;
+        protocol::write_frame(&mut self.writer, &RequestFrame { id, request })?;
+        let frame: ResponseFrame = protocol::read_frame(&mut self.reader)?
+            .ok_or_else(|| CustomError::Protocol("server closed the connection".to_owned()))?;
+        if frame.id != id {
+            return Err(CustomError::UnexpectedResponse);
+        }
+        match frame.response {
+            Response::Ok(value) => Ok(value),
+            Response::Err(e) => Err(e.into()),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! Hint files, which let `open` rebuild the index without reading the values.
+//!
+//! A sealed log file `N.bin` gets a `N.hint` next to it, listing for each record of the
+//! log file its key, offset and length, and whether it removes the key. The first record
+//! of a hint file holds the length of the log file it describes, so a hint that does not
+//! match its log file is ignored and the log file is replayed instead.
+use super::record::{read_record, write_record, Transaction};
+use crate::error::Result;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+/// The extension of the hint files, which are named after the log file they describe
+pub(super) const HINT_EXTENSION: &str = "hint";
+
+/// What `open` needs to know about a log record to index it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(super) struct Hint {
+    pub(super) key: String,
+    /// The offset of the record in its log file
+    pub(super) offset: u64,
+    /// The length of the record in bytes
+    pub(super) len: u64,
+    /// Whether the record is a tombstone
+    pub(super) removed: bool,
+}
+
+impl Hint {
+    /// The hint of `transaction`, written at `offset` and `len` bytes long
+    pub(super) fn new(transaction: &Transaction, offset: u64, len: u64) -> Hint {
+        let (key, removed) = match transaction {
+            Transaction::Set(key, _) => (key, false),
+            Transaction::Remove(key) => (key, true),
+        };
+        Hint {
+            key: key.clone(),
+            offset,
+            len,
+            removed,
+        }
+    }
+}
+
+pub(super) fn hint_file_path(folder_path: &Path, file_index: u32) -> PathBuf {
+    folder_path.join(format!("{}.{}", file_index, HINT_EXTENSION))
+}
+
+/// Write the hint file of the log file `file_index`, which is `log_len` bytes long.
+/// The hints are written to a temporary file first, so a crash never leaves half a hint file.
+pub(super) fn write_hint_file(
+    folder_path: &Path,
+    file_index: u32,
+    log_len: u64,
+    hints: &[Hint],
+) -> Result<()> {
+    let path = hint_file_path(folder_path, file_index);
+    let temp_path = path.with_extension(format!("{}.tmp", HINT_EXTENSION));
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+    write_record(&mut writer, &log_len)?;
+    for hint in hints {
+        write_record(&mut writer, hint)?;
+    }
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    fs::rename(&temp_path, &path)?;
+    Ok(())
+}
+
+/// Read the hints of the log file `file_index`, which is `log_len` bytes long.
+/// Returns `None` if there is no usable hint file, so the log file has to be replayed.
+pub(super) fn read_hint_file(
+    folder_path: &Path,
+    file_index: u32,
+    log_len: u64,
+) -> Result<Option<Vec<Hint>>> {
+    let file = match File::open(hint_file_path(folder_path, file_index)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let hint_len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+
+    // A damaged hint file only costs a replay of its log file.
+    let mut read_hints = || -> Result<Option<Vec<Hint>>> {
+        let Some((described_len, mut pos)) = read_record::<_, u64>(&mut reader, file_index, 0)?
+        else {
+            return Ok(None);
+        };
+        if described_len != log_len {
+            return Ok(None);
+        }
+        let mut hints = Vec::new();
+        while let Some((hint, len)) = read_record(&mut reader, file_index, pos)? {
+            hints.push(hint);
+            pos += len;
+        }
+        Ok((pos == hint_len).then_some(hints))
+    };
+    match read_hints() {
+        Ok(Some(hints)) => Ok(Some(hints)),
+        Ok(None) | Err(_) => {
+            debug!(file = file_index; "Ignoring hint file that does not match its log file");
+            Ok(None)
+        }
+    }
+}
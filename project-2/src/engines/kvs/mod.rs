@@ -0,0 +1,1356 @@
+use self::hint::{hint_file_path, read_hint_file, write_hint_file, Hint, HINT_EXTENSION};
+use self::record::{
+    read_log_header, read_record, write_log_header, write_record, LogHeader, Transaction,
+    FORMAT_VERSION, LOG_HEADER_LEN,
+};
+use super::lock::DirLock;
+use super::manifest::Manifest;
+use super::{prefix_range, CompactionReport, KvsEngine};
+use crate::error::{CustomError, Result};
+use arc_swap::ArcSwap;
+use imbl::OrdMap;
+use log::{debug, error, warn};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::ffi::OsStr;
+use std::fs::{self, remove_file, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read};
+use std::ops::RangeBounds;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+mod hint;
+mod options;
+mod policy;
+mod record;
+mod scan;
+mod snapshot;
+mod upgrade;
+
+pub use self::options::{KvStoreOptions, SyncPolicy};
+pub use self::policy::{CompactionPolicy, FileStats, GarbageRatioPolicy};
+pub use self::scan::Scan;
+pub use self::snapshot::Snapshot;
+
+/// The extension of the log files, which are named after their number
+const LOG_EXTENSION: &str = "bin";
+/// The extension of a compaction output that is not complete yet
+const TEMP_EXTENSION: &str = "tmp";
+/// The engine name in the MANIFEST
+const ENGINE: &str = "kvs";
+
+/// The index from keys to where their latest value lives:
+/// the file number, the offset in the file and the length of the record
+type Index = OrdMap<String, (u32, u64, u64)>;
+
+/// The log-structured Key Value Store, which keeps an index in memory and the data in log files
+///
+/// `KvStore` is a cheap, cloneable handle; clones share the same store and can be
+/// moved to other threads. Writes are serialized, while reads never wait for them:
+/// every write publishes a new version of the index and readers use whichever
+/// version was current when they started. A version holds the log files it points into
+/// open, and reads use positional reads on them, so concurrent reads share the files.
+///
+/// Files full of stale values are compacted on a background thread while writes
+/// continue, as the `CompactionPolicy` decides; dropping the last clone waits for a running compaction to finish.
+/// # Examples
+/// ```
+/// use kvs::KvStore;
+/// let store = KvStore::open(std::env::current_dir()?)?;
+/// store.set("key".to_string(), "value".to_string())?;
+/// ```
+#[derive(Clone)]
+pub struct KvStore {
+    /// The state shared by every clone and the compaction thread
+    shared: Arc<Shared>,
+    /// Only held by clones, so its drop tells when the last one is gone
+    _handle: Arc<StoreHandle>,
+}
+
+/// The state shared by every clone and the compaction thread
+struct Shared {
+    /// The folder that the log files are stored in
+    folder_path: PathBuf,
+    /// The current version of the store
+    version: ArcSwap<Version>,
+    /// The state only writers touch
+    writer: Mutex<KvStoreWriter>,
+    /// How many compactions have finished
+    compactions: AtomicU64,
+    /// The sequence number of the last write known to be on disk
+    synced_seq: AtomicU64,
+    /// How many times a log file was synced
+    syncs: AtomicU64,
+    /// The bytes of incomplete records cut off the log files by `open`
+    discarded_bytes: u64,
+    /// When written records are synced to disk
+    sync_policy: SyncPolicy,
+    /// The size at which the active file is sealed and writes move on to a new file
+    max_file_size: u64,
+    /// Decides which files to compact
+    compaction_policy: Arc<dyn CompactionPolicy>,
+    /// Lets one `KvStore::compact` run at a time
+    manual_compaction: Mutex<()>,
+    /// The writes waiting for a group commit
+    group_commit: Mutex<GroupCommit>,
+    /// Signalled whenever a group commit finishes
+    group_committed: Condvar,
+    /// Whether the store was opened with `open_read_only`
+    read_only: bool,
+    /// Keeps other processes out of the directory until the last user of the store is gone;
+    /// read-only stores don't take it
+    _lock: Option<DirLock>,
+}
+
+/// The progress of `SyncPolicy::GroupCommit`, in write sequence numbers
+struct GroupCommit {
+    /// The last write appended
+    written: u64,
+    /// The file the last write went to
+    file: Option<Arc<File>>,
+    /// Every write up to this one is on disk
+    synced: u64,
+    /// Whether a writer is syncing on behalf of the others right now
+    syncing: bool,
+}
+
+/// A consistent view of the store: the index and the log files it points into.
+/// Holding a version keeps its files on disk.
+#[derive(Clone)]
+struct Version {
+    index: Index,
+    files: BTreeMap<u32, Arc<LogFile>>,
+    /// The sequence number of the last write in the index
+    seq: u64,
+}
+
+/// A log file on disk.
+/// Once compaction retires it, it is deleted as soon as no version uses it anymore.
+struct LogFile {
+    /// The file, open for reads; set when the active file is created by its first write
+    file: OnceLock<Arc<File>>,
+    /// Set when compaction copied the live records of this file elsewhere
+    retired: OnceLock<Arc<RetiredFiles>>,
+}
+
+/// The files one compaction replaced, deleted together once none of them is used anymore.
+struct RetiredFiles {
+    /// The paths, in ascending file number order, each log file right after its hint file
+    paths: Vec<PathBuf>,
+}
+
+/// Everything a write needs, kept behind one lock so only one write happens at a time
+struct KvStoreWriter {
+    /// The file that new records are appended to
+    active: u32,
+    /// The active file, once it is opened for appending
+    active_file: Option<Arc<File>>,
+    /// The length of the active file while it is open
+    active_len: u64,
+    /// The hints of the records in the active file, written out once it is sealed
+    active_hints: Vec<Hint>,
+    /// The sequence number of the last write
+    seq: u64,
+    /// The writes since the last sync
+    unsynced: u32,
+    /// When the active file was last synced
+    last_sync: Instant,
+    /// The number the next new file gets
+    next_file: u32,
+    /// The files that the key value pairs are stored in, with how much of each is stale
+    files: BTreeMap<u32, FileStats>,
+    /// The last compaction started, which may still be running
+    compaction: Option<JoinHandle<()>>,
+    /// Whether `KvStore::compact` is compacting on the caller's thread
+    compacting: bool,
+    /// Set when a failed append couldn't be cut off the active file, so the offsets of
+    /// further appends would be wrong
+    failed: bool,
+}
+
+/// Waits for a running compaction when the last clone of a store is dropped,
+/// so the files are left alone once the store is closed.
+struct StoreHandle {
+    shared: Arc<Shared>,
+}
+
+/// Marks a `KvStore::compact` as running until it ends, even by a panic
+struct ManualCompaction<'a> {
+    shared: &'a Shared,
+}
+
+/// Reads a file from a position on with positional reads, which leave the file offset alone
+struct PositionalReader<'a> {
+    file: &'a File,
+    pos: u64,
+}
+
+/// A record compaction copied to its output file
+struct MovedRecord {
+    key: String,
+    /// Where the record was: the file number, the offset in the file and its length
+    from: (u32, u64, u64),
+    /// The offset of the copy in the output file
+    pos: u64,
+}
+
+/// What compaction wrote to its output file
+struct CompactionOutput {
+    /// The values it copied
+    moved: Vec<MovedRecord>,
+    /// The bytes of the tombstones it had to keep
+    tombstone_bytes: u64,
+}
+
+impl KvStore {
+    /// Open a Key Value Store from a file, with the default options
+    pub fn open<F: AsRef<Path>>(path: F) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreOptions::default())
+    }
+
+    /// Open a Key Value Store without writing to its directory: nothing is created,
+    /// repaired or compacted, and `set` and `remove` fail with `CustomError::ReadOnly`.
+    /// It takes no lock, so it works on read-only mounts and next to a running writer;
+    /// it sees the store as it was when opened, and a value the writer compacts away
+    /// afterwards can't be read anymore.
+    pub fn open_read_only<F: AsRef<Path>>(path: F) -> Result<KvStore> {
+        let options = KvStoreOptions {
+            read_only: true,
+            ..KvStoreOptions::default()
+        };
+        KvStore::open_with(path, options)
+    }
+
+    /// The on-disk format version this build writes
+    pub const FORMAT_VERSION: u32 = FORMAT_VERSION;
+
+    /// Migrate the store in `path` from an older on-disk format to the current one, in place.
+    /// Returns the format version the store was written in; a store that is already in the
+    /// current format is left as it is.
+    pub fn upgrade<F: AsRef<Path>>(path: F) -> Result<u32> {
+        upgrade::upgrade(path.as_ref())
+    }
+
+    /// Write the store in `from`, in any format, to a new store in `to` in the current format.
+    /// `from` is left as it is. Returns the format version `from` was written in.
+    pub fn upgrade_into<F: AsRef<Path>, T: AsRef<Path>>(from: F, to: T) -> Result<u32> {
+        upgrade::upgrade_into(from.as_ref(), to.as_ref())
+    }
+
+    /// Open a Key Value Store from a file
+    /// Opening a Key Value Store will read all the files in the folder and
+    /// load all the key value pairs
+    /// The KVStore holds
+    /// 1) A map of keys to file numbers and offsets - index
+    /// 2) a folder path that holds the files - folder_path
+    /// 3) A map of file numbers to how much of each file is stale - files
+    pub fn open_with<F: AsRef<Path>>(path: F, options: KvStoreOptions) -> Result<KvStore> {
+        let mut storage: Index = OrdMap::new();
+        let mut files: BTreeMap<u32, FileStats> = BTreeMap::new();
+        let mut file_indexes: BTreeSet<u32> = BTreeSet::new();
+        let mut hint_indexes: BTreeSet<u32> = BTreeSet::new();
+        let mut active_hints = Vec::new();
+        let mut discarded_bytes = 0;
+        let folder_path = PathBuf::from(path.as_ref());
+        let read_only = options.read_only;
+        // Before anything is read, so no other writer changes the files under us
+        let lock = match read_only {
+            true => None,
+            false => Some(DirLock::acquire(&folder_path)?),
+        };
+
+        let manifest = Manifest::load(&folder_path)?;
+        match &manifest {
+            Some(manifest)
+                if manifest.engine == ENGINE && manifest.format_version < FORMAT_VERSION =>
+            {
+                return Err(needs_upgrade(&format!(
+                    "format version {}",
+                    manifest.format_version
+                )));
+            }
+            Some(manifest) => manifest.check(ENGINE, FORMAT_VERSION)?,
+            None => {}
+        }
+
+        // Collect file indexes
+        for entry in fs::read_dir(&path)? {
+            let entry = entry?;
+            let path = entry.path();
+
+            // Skip directories
+            if path.is_dir() {
+                continue;
+            }
+
+            // A compaction that crashed before its output was renamed into place
+            // leaves a temporary file; the files it compacted are all still there.
+            if path.extension() == Some(OsStr::new(TEMP_EXTENSION)) {
+                if !read_only {
+                    remove_file(&path)?;
+                }
+                continue;
+            }
+            let indexes = if path.extension() == Some(OsStr::new(LOG_EXTENSION)) {
+                &mut file_indexes
+            } else if path.extension() == Some(OsStr::new(HINT_EXTENSION)) {
+                &mut hint_indexes
+            } else {
+                continue;
+            };
+
+            // Parse file index from the file name
+            if let Some(file_stem) = path.file_stem() {
+                if let Ok(file_index) = file_stem.to_string_lossy().parse::<u32>() {
+                    indexes.insert(file_index);
+                }
+            }
+        }
+
+        // Without a manifest, the directory is a new store.
+        let (file_indexes, active) = match &manifest {
+            Some(manifest) => {
+                let live: BTreeSet<u32> = manifest.files.iter().copied().collect();
+                // Anything else is left over from a compaction that was interrupted
+                // before the manifest took in its output, or after it dropped the inputs.
+                for file_index in file_indexes.difference(&live) {
+                    if !read_only {
+                        remove_file(log_file_path(&folder_path, *file_index))?;
+                    }
+                }
+                (live, manifest.active)
+            }
+            // Log files without a manifest were written before format version 3
+            None if !file_indexes.is_empty() => return Err(needs_upgrade("an older format")),
+            None => (file_indexes, 0),
+        };
+
+        // A hint without its log file is left over from a compaction; its number may be reused.
+        for file_index in hint_indexes.difference(&file_indexes) {
+            if !read_only {
+                remove_file(hint_file_path(&folder_path, *file_index))?;
+            }
+        }
+
+        // Process files in sorted order
+        for file_index in file_indexes {
+            let file_path = log_file_path(&folder_path, file_index);
+            // The active file is only created by its first write. A sealed file that was never
+            // written to holds nothing, and is left out of the next manifest.
+            if !file_path.exists() {
+                continue;
+            }
+            files.entry(file_index).or_default();
+            let file_len = fs::metadata(&file_path)?.len();
+            // Every file but the active one is sealed, and its hint file lists its records
+            let sealed = file_index != active;
+            let hints = match sealed {
+                true => read_hint_file(&folder_path, file_index, file_len)?,
+                false => None,
+            };
+            let hints = match hints {
+                Some(hints) => hints,
+                None => {
+                    let (hints, valid_len) = replay_log_file(&file_path, file_index)?;
+
+                    // Sealed files were synced whole, so one that ends early lost data.
+                    if valid_len < file_len && sealed {
+                        return Err(CustomError::Corrupted {
+                            file: file_index,
+                            offset: valid_len,
+                        });
+                    }
+                    // A crash in the middle of an append leaves an incomplete record at the end
+                    // of the active file. Cut it off, or later appends would land behind it and
+                    // be lost on replay. A read-only store only reads up to it; the record may
+                    // still be being written.
+                    if valid_len < file_len && !read_only {
+                        warn!(
+                            file = file_index,
+                            offset = valid_len,
+                            bytes = file_len - valid_len;
+                            "Discarding incomplete record"
+                        );
+                        let file = OpenOptions::new().write(true).open(&file_path)?;
+                        file.set_len(valid_len)?;
+                        file.sync_all()?;
+                        discarded_bytes += file_len - valid_len;
+                    }
+                    if sealed && !read_only {
+                        write_hint_file_or_warn(&folder_path, file_index, valid_len, &hints);
+                    }
+                    hints
+                }
+            };
+
+            for hint in &hints {
+                files.entry(file_index).or_default().total_bytes += hint.len;
+                let old = if hint.removed {
+                    let old = storage.remove(&hint.key);
+                    // A tombstone is garbage once no older file has a value for it to shadow
+                    if old.is_none_or(|(old_file_index, _, _)| old_file_index == file_index) {
+                        files.entry(file_index).or_default().stale_bytes += hint.len;
+                    }
+                    old
+                } else {
+                    let location = (file_index, hint.offset, hint.len);
+                    storage.insert(hint.key.clone(), location)
+                };
+                // The value the record overwrote or removed is stale now
+                if let Some((old_file_index, _, old_len)) = old {
+                    files.entry(old_file_index).or_default().stale_bytes += old_len;
+                }
+            }
+            if !sealed {
+                active_hints = hints;
+            }
+        }
+
+        // New records go to the active file
+        files.entry(active).or_default();
+        if manifest.is_none() && !read_only {
+            store_manifest(&folder_path, files.keys().copied(), active)?;
+        }
+        let next_file = files.keys().max().map_or(0, |max| max + 1);
+
+        let mut log_files = BTreeMap::new();
+        for &file_index in files.keys() {
+            let file_path = log_file_path(&folder_path, file_index);
+            let log_file = match file_path.exists() {
+                true => LogFile::open(&file_path)?,
+                false => LogFile::new(),
+            };
+            log_files.insert(file_index, log_file);
+        }
+        let version = Version {
+            index: storage,
+            files: log_files,
+            seq: 0,
+        };
+        let shared = Arc::new(Shared {
+            folder_path,
+            version: ArcSwap::from_pointee(version),
+            writer: Mutex::new(KvStoreWriter {
+                active,
+                active_file: None,
+                active_len: 0,
+                active_hints,
+                seq: 0,
+                unsynced: 0,
+                last_sync: Instant::now(),
+                next_file,
+                files,
+                compaction: None,
+                compacting: false,
+                failed: false,
+            }),
+            compactions: AtomicU64::new(0),
+            synced_seq: AtomicU64::new(0),
+            syncs: AtomicU64::new(0),
+            discarded_bytes,
+            sync_policy: options.sync_policy,
+            max_file_size: options.max_file_size,
+            compaction_policy: options.policy(),
+            manual_compaction: Mutex::new(()),
+            group_commit: Mutex::new(GroupCommit {
+                written: 0,
+                file: None,
+                synced: 0,
+                syncing: false,
+            }),
+            group_committed: Condvar::new(),
+            read_only,
+            _lock: lock,
+        });
+        Ok(KvStore {
+            _handle: Arc::new(StoreHandle {
+                shared: Arc::clone(&shared),
+            }),
+            shared,
+        })
+    }
+
+    /// Set a key to a value.
+    /// If the key already exists, the old value is marked as expired.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        let seq = self.shared.set(&mut self.shared.writer(), key, value)?;
+        self.shared.wait_for_group_commit(seq)
+    }
+
+    /// Get the value associated with a key.
+    /// Returns `None` if the key does not exist.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.get_in(&self.shared.version.load_full(), key)
+    }
+
+    /// List every key in the store, in ascending order.
+    pub fn keys(&self) -> Result<Vec<String>> {
+        Ok(self.shared.version.load().index.keys().cloned().collect())
+    }
+
+    /// Iterate over the key/value pairs whose keys fall in `range`, in ascending key order.
+    /// The iterator sees the store as it is now; see `Scan`.
+    pub fn scan<R: RangeBounds<String>>(&self, range: R) -> Scan {
+        self.snapshot().scan(range)
+    }
+
+    /// Iterate over the key/value pairs whose keys start with `prefix`, in ascending key order
+    pub fn scan_prefix(&self, prefix: &str) -> Scan {
+        self.snapshot().scan_prefix(prefix)
+    }
+
+    /// Take a read-only view of the store as it is now, which later writes and compactions
+    /// don't change; see `Snapshot`.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(self.clone(), self.shared.version.load_full())
+    }
+
+    /// Remove a key and its associated value from the store.
+    /// Returns an error if the key does not exist.
+    pub fn remove(&self, key: String) -> Result<()> {
+        let seq = self.shared.remove(&mut self.shared.writer(), key)?;
+        self.shared.wait_for_group_commit(seq)
+    }
+
+    /// Compact every file with stale data now, on the calling thread, and report what was done.
+    /// A compaction running in the background is waited for first. The active file is sealed
+    /// and compacted too, so no stale data is left behind.
+    ///
+    /// The compacted files are deleted once no `Snapshot` reads from them anymore; the report
+    /// only counts the space that was freed by the time it returns.
+    pub fn compact(&self) -> Result<CompactionReport> {
+        if self.shared.read_only {
+            return Err(CustomError::ReadOnly);
+        }
+        let started = Instant::now();
+        let _manual = self
+            .shared
+            .manual_compaction
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let Some((candidates, output, size_before)) = self.shared.start_manual_compaction()? else {
+            return Ok(CompactionReport {
+                duration: started.elapsed(),
+                ..CompactionReport::default()
+            });
+        };
+
+        let _compacting = ManualCompaction {
+            shared: &self.shared,
+        };
+        self.shared.run_compaction(&candidates, output)?;
+        // Candidates a snapshot still reads from are still there
+        let size_after = self.shared.files_size(&candidates) + self.shared.files_size(&[output]);
+        Ok(CompactionReport {
+            files_rewritten: candidates.len(),
+            bytes_reclaimed: size_before.saturating_sub(size_after),
+            duration: started.elapsed(),
+        })
+    }
+
+    /// The sequence number of the last write known to be on disk, numbered like
+    /// `Snapshot::seq`. A crash of the machine may lose the writes after it, but none before.
+    pub fn synced_seq(&self) -> u64 {
+        self.shared.synced_seq.load(Ordering::SeqCst)
+    }
+
+    /// How many times the log was synced to disk since the store was opened
+    pub fn syncs(&self) -> u64 {
+        self.shared.syncs.load(Ordering::SeqCst)
+    }
+
+    /// The number of bytes `open` discarded from the end of the active log file because they
+    /// held a record that was only partly written, e.g. when the process crashed mid-write.
+    pub fn discarded_bytes(&self) -> u64 {
+        self.shared.discarded_bytes
+    }
+
+    /// Get the value of a key in `version`.
+    /// The version keeps the file we read from on disk, even if compaction moves the value meanwhile.
+    fn get_in(&self, version: &Version, key: String) -> Result<Option<String>> {
+        match version.index.get(&key) {
+            Some(&(file_index, offset, _)) => {
+                self.read_value(version, &key, file_index, offset).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Read the value of `key` from the record at `offset` in the given file of `version`
+    fn read_value(
+        &self,
+        version: &Version,
+        key: &str,
+        file_index: u32,
+        offset: u64,
+    ) -> Result<String> {
+        let file = version
+            .files
+            .get(&file_index)
+            .and_then(|log_file| log_file.file.get())
+            .ok_or(CustomError::StaleIndex)?;
+        match read_record_at(file, file_index, offset)? {
+            Transaction::Set(record_key, value) if record_key == key => Ok(value),
+            // The index pointed at a record of another key; the log does not match the index.
+            _ => Err(CustomError::StaleIndex),
+        }
+    }
+}
+
+impl Shared {
+    fn writer(&self) -> MutexGuard<'_, KvStoreWriter> {
+        // A panicking writer leaves the files and the index consistent, so the poison is ignored.
+        self.writer.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns the sequence number of the write if it waits for a group commit
+    fn set(
+        self: &Arc<Self>,
+        writer: &mut KvStoreWriter,
+        key: String,
+        value: String,
+    ) -> Result<Option<u64>> {
+        if self.read_only {
+            return Err(CustomError::ReadOnly);
+        }
+        self.roll_over_if_full(writer)?;
+
+        // Serialize and append the `Set` transaction to the file
+        let (pos, len) = self.append(writer, &Transaction::Set(key.clone(), value))?;
+        let seq = self.sync_write(writer)?;
+
+        // Publish the new location, marking the old entry as stale
+        let mut version = Version::clone(&self.version.load());
+        let location = (writer.active, pos, len);
+        if let Some((old_file_index, _, old_len)) = version.index.insert(key, location) {
+            writer.files.entry(old_file_index).or_default().stale_bytes += old_len;
+        }
+        version.seq = writer.seq;
+        self.version.store(Arc::new(version));
+        Ok(seq)
+    }
+
+    /// Returns the sequence number of the write if it waits for a group commit
+    fn remove(self: &Arc<Self>, writer: &mut KvStoreWriter, key: String) -> Result<Option<u64>> {
+        if self.read_only {
+            return Err(CustomError::ReadOnly);
+        }
+        // Check if the key exists
+        if !self.version.load().index.contains_key(&key) {
+            return Err(CustomError::KeyNotFound);
+        }
+
+        self.roll_over_if_full(writer)?;
+
+        // Serialize and append the `Remove` transaction to the file
+        let (_, len) = self.append(writer, &Transaction::Remove(key.clone()))?;
+        let seq = self.sync_write(writer)?;
+
+        // Publish the index without the key, marking the old entry and the tombstone as stale
+        let mut version = Version::clone(&self.version.load());
+        if let Some((file_index, _, old_len)) = version.index.remove(&key) {
+            writer.files.entry(file_index).or_default().stale_bytes += old_len;
+        }
+        writer.files.entry(writer.active).or_default().stale_bytes += len;
+        version.seq = writer.seq;
+        self.version.store(Arc::new(version));
+        Ok(seq)
+    }
+
+    /// Continue in a new file if the active file has reached the size limit
+    fn roll_over_if_full(self: &Arc<Self>, writer: &mut KvStoreWriter) -> Result<()> {
+        self.open_active_file(writer)?;
+        if writer.active_len >= self.max_file_size {
+            self.rotate(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Sync the active file to disk
+    fn flush(&self, writer: &mut KvStoreWriter) -> Result<()> {
+        if let Some(file) = &writer.active_file {
+            file.sync_data()?;
+            self.syncs.fetch_add(1, Ordering::SeqCst);
+        }
+        // Files before the active one were synced when writes moved on from them.
+        self.synced_seq.fetch_max(writer.seq, Ordering::SeqCst);
+        writer.unsynced = 0;
+        writer.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// Apply the sync policy to the write just appended.
+    /// A write left to a group commit returns its sequence number, to wait for
+    /// once the writer lock is released.
+    fn sync_write(&self, writer: &mut KvStoreWriter) -> Result<Option<u64>> {
+        match self.sync_policy {
+            SyncPolicy::Never => {}
+            SyncPolicy::Always => self.flush(writer)?,
+            SyncPolicy::EveryN(n) => {
+                writer.unsynced += 1;
+                if writer.unsynced >= n {
+                    self.flush(writer)?;
+                }
+            }
+            SyncPolicy::Interval(interval) => {
+                if writer.last_sync.elapsed() >= interval {
+                    self.flush(writer)?;
+                }
+            }
+            SyncPolicy::GroupCommit => {
+                let mut group_commit = self.group_commit();
+                group_commit.written = writer.seq;
+                group_commit.file = writer.active_file.clone();
+                return Ok(Some(writer.seq));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Wait until the write `seq` is on disk.
+    /// The first writer to find no sync running syncs for everyone written so far;
+    /// the others wait for it and are done if it covered them.
+    fn wait_for_group_commit(&self, seq: Option<u64>) -> Result<()> {
+        let Some(seq) = seq else {
+            return Ok(());
+        };
+        let mut group_commit = self.group_commit();
+        while group_commit.synced < seq {
+            if group_commit.syncing {
+                group_commit = self
+                    .group_committed
+                    .wait(group_commit)
+                    .unwrap_or_else(PoisonError::into_inner);
+                continue;
+            }
+
+            group_commit.syncing = true;
+            let written = group_commit.written;
+            let file = group_commit.file.clone();
+            drop(group_commit);
+            // Files before the active one were synced when writes moved on from them.
+            let synced = file.map_or(Ok(()), |file| file.sync_data());
+            group_commit = self.group_commit();
+            group_commit.syncing = false;
+            if synced.is_ok() {
+                group_commit.synced = group_commit.synced.max(written);
+                self.syncs.fetch_add(1, Ordering::SeqCst);
+                self.synced_seq.fetch_max(written, Ordering::SeqCst);
+            }
+            self.group_committed.notify_all();
+            synced?;
+        }
+        Ok(())
+    }
+
+    fn group_commit(&self) -> MutexGuard<'_, GroupCommit> {
+        self.group_commit
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Close the active file and continue in a new one.
+    /// If no compaction is running and the compaction policy picks any files,
+    /// they are compacted on a background thread.
+    fn rotate(self: &Arc<Self>, writer: &mut KvStoreWriter) -> Result<()> {
+        let running = writer.compacting
+            || writer
+                .compaction
+                .as_ref()
+                .is_some_and(|compaction| !compaction.is_finished());
+        let candidates = match running {
+            true => Vec::new(),
+            false => self.select_candidates(writer),
+        };
+        if candidates.is_empty() {
+            return self.start_new_file(writer);
+        }
+
+        // The compacted file is numbered after the files it replaces but before the new
+        // active file, so replaying the files in order still ends with the latest values.
+        let output = writer.next_file;
+        writer.next_file += 1;
+        self.start_new_file(writer)?;
+
+        if let Some(finished) = writer.compaction.take() {
+            let _ = finished.join();
+        }
+        let shared = Arc::clone(self);
+        writer.compaction = Some(thread::spawn(move || shared.compact(candidates, output)));
+        Ok(())
+    }
+
+    /// Ask the compaction policy which files to compact, keeping only files that exist
+    fn select_candidates(&self, writer: &KvStoreWriter) -> Vec<u32> {
+        let disk_usage = writer.files.values().map(|stats| stats.total_bytes).sum();
+        let mut candidates = self.compaction_policy.select(&writer.files, disk_usage);
+        candidates.retain(|file_index| writer.files.contains_key(file_index));
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates
+    }
+
+    /// Make the next file number the active file.
+    /// The old active file is synced first, so a sync of the active file covers every write
+    /// before it. An old active file without records is dropped rather than sealed.
+    fn start_new_file(&self, writer: &mut KvStoreWriter) -> Result<()> {
+        // Sealed files are never synced again, so `flush` only has to sync the active one.
+        self.flush(writer)?;
+        let empty = writer
+            .files
+            .get(&writer.active)
+            .is_none_or(|stats| stats.total_bytes == 0);
+        if !writer.active_hints.is_empty() {
+            self.open_active_file(writer)?;
+            write_hint_file_or_warn(
+                &self.folder_path,
+                writer.active,
+                writer.active_len,
+                &writer.active_hints,
+            );
+            writer.active_hints.clear();
+        }
+        // The manifest must list the new file before anything is written to it.
+        let active = writer.next_file;
+        let mut files = writer.files.clone();
+        if empty {
+            files.remove(&writer.active);
+        }
+        files.insert(active, FileStats::default());
+        store_manifest(&self.folder_path, files.keys().copied(), active)?;
+
+        let mut version = Version::clone(&self.version.load());
+        if empty {
+            // At most a log header was written. `open` deletes the file if this fails,
+            // since the manifest no longer lists it.
+            version.files.remove(&writer.active);
+            match remove_file(self.file_path(writer.active)) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    warn!(file = writer.active, error:% = e; "Failed to delete empty log file")
+                }
+            }
+        }
+        writer.active_file = None;
+        writer.active = active;
+        writer.next_file += 1;
+        writer.files = files;
+
+        version.files.insert(writer.active, LogFile::new());
+        self.version.store(Arc::new(version));
+        Ok(())
+    }
+
+    /// Open the active file for appending, unless it is open already
+    fn open_active_file<'a>(&self, writer: &'a mut KvStoreWriter) -> Result<&'a Arc<File>> {
+        if writer.active_file.is_none() {
+            // Open the file in append mode; reads of the records appended to it share it.
+            let file = OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(self.file_path(writer.active))?;
+            writer.active_len = file.metadata()?.len();
+            if writer.active_len == 0 {
+                write_log_header(&mut &file)?;
+                writer.active_len = LOG_HEADER_LEN;
+            }
+            let file = Arc::new(file);
+            // An active file that existed when the store was opened is open for reads already.
+            if let Some(log_file) = self.version.load().files.get(&writer.active) {
+                let _ = log_file.file.set(Arc::clone(&file));
+            }
+            writer.active_file = Some(file);
+        }
+        Ok(writer
+            .active_file
+            .as_ref()
+            .expect("the active file was just opened"))
+    }
+
+    /// Append a transaction to the end of the active file,
+    /// returning the offset it was written at and its length
+    fn append(&self, writer: &mut KvStoreWriter, transaction: &Transaction) -> Result<(u64, u64)> {
+        if writer.failed {
+            return Err(CustomError::WriteFailed);
+        }
+        let file = Arc::clone(self.open_active_file(writer)?);
+        let pos = writer.active_len;
+        match write_record(&mut &*file, transaction) {
+            Ok(len) => {
+                writer.active_len += len;
+                writer.active_hints.push(Hint::new(transaction, pos, len));
+                writer.files.entry(writer.active).or_default().total_bytes += len;
+                writer.seq += 1;
+                Ok((pos, len))
+            }
+            Err(e) => {
+                // Don't leave half a record for the next append to land behind.
+                if let Err(truncate_error) = file.set_len(pos) {
+                    error!(error:% = truncate_error; "Failed to cut a failed write off the log file");
+                    writer.failed = true;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Compact the candidate files into the `output` file, in the background.
+    /// Writes continue in the active file meanwhile; the index only switches over
+    /// to the compacted file once it is complete.
+    fn compact(&self, candidates: Vec<u32>, output: u32) {
+        if let Err(e) = self.run_compaction(&candidates, output) {
+            error!(error:% = e; "Compaction failed");
+        }
+    }
+
+    /// The size of the given log files and their hint files on disk
+    fn files_size(&self, files: &[u32]) -> u64 {
+        files
+            .iter()
+            .flat_map(|&file_index| {
+                [
+                    self.file_path(file_index),
+                    hint_file_path(&self.folder_path, file_index),
+                ]
+            })
+            .filter_map(|path| fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    }
+
+    /// Compact the candidate files into the `output` file
+    fn run_compaction(&self, candidates: &[u32], output: u32) -> Result<()> {
+        debug!(files:? = candidates, output = output; "Compaction started");
+        let compacted = self
+            .copy_live_records(candidates, output)
+            .and_then(|compacted| self.finish_compaction(candidates, output, compacted));
+        if compacted.is_err() {
+            // The candidates are untouched, so only the output has to go.
+            let _ = remove_file(self.temp_file_path(output));
+            let _ = remove_file(hint_file_path(&self.folder_path, output));
+            let _ = remove_file(self.file_path(output));
+        }
+        compacted?;
+        debug!(output = output; "Compaction finished");
+        Ok(())
+    }
+
+    /// Wait for a compaction running in the background, then pick every file with stale data
+    /// and seal the active file, so the output is numbered between the two.
+    /// Returns the candidates, the output and the size of the candidates before,
+    /// or `None` if there is nothing to compact.
+    fn start_manual_compaction(&self) -> Result<Option<(Vec<u32>, u32, u64)>> {
+        loop {
+            let mut writer = self.writer();
+            if let Some(compaction) = writer.compaction.take() {
+                // The compaction needs the writer lock to finish
+                drop(writer);
+                let _ = compaction.join();
+                continue;
+            }
+
+            let candidates: Vec<u32> = writer
+                .files
+                .iter()
+                .filter(|(_, stats)| stats.stale_bytes > 0)
+                .map(|(&file_index, _)| file_index)
+                .collect();
+            if candidates.is_empty() {
+                return Ok(None);
+            }
+            // Measured before sealing, which writes the hint file of the active file
+            let size_before = self.files_size(&candidates);
+            let output = writer.next_file;
+            writer.next_file += 1;
+            self.start_new_file(&mut writer)?;
+            writer.compacting = true;
+            return Ok(Some((candidates, output, size_before)));
+        }
+    }
+
+    /// Read the candidate files and discard all logs that are expired
+    /// (ones that already have a value in the index that is not in the same file & offset).
+    /// The remaining logs are written to the `output` file.
+    /// Returns `None` if nothing was left to copy and there is no output.
+    fn copy_live_records(
+        &self,
+        candidates: &[u32],
+        output: u32,
+    ) -> Result<Option<CompactionOutput>> {
+        // A tombstone of a key no file outside the compaction has a value for
+        // shadows nothing once the candidates are gone.
+        let shadowed = self.shadowed_keys(candidates, output)?;
+        let temp_path = self.temp_file_path(output);
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        write_log_header(&mut writer)?;
+        let mut output_pos = LOG_HEADER_LEN;
+        let mut moved = Vec::new();
+        let mut tombstone_bytes = 0;
+        let mut hints = Vec::new();
+
+        for &file_index in candidates {
+            let version = self.version.load_full();
+            let file = File::open(self.file_path(file_index))?;
+            let mut reader = BufReader::new(&file);
+
+            if !check_log_header(&mut reader, file_index)? {
+                continue;
+            }
+            let mut pos = LOG_HEADER_LEN;
+            while let Some((transaction, len)) = read_record(&mut reader, file_index, pos)? {
+                let keep = match &transaction {
+                    // Only keep the value if the index still points at it
+                    Transaction::Set(key, _) => {
+                        let live = version.index.get(key) == Some(&(file_index, pos, len));
+                        if live {
+                            moved.push(MovedRecord {
+                                key: key.clone(),
+                                from: (file_index, pos, len),
+                                pos: output_pos,
+                            });
+                        }
+                        live
+                    }
+                    // Keep the tombstone while the key stays removed and an older value of it
+                    // outlives the compaction; replay would bring that value back otherwise.
+                    Transaction::Remove(key) => {
+                        let needed = !version.index.contains_key(key) && shadowed.contains(key);
+                        if needed {
+                            tombstone_bytes += len;
+                        }
+                        needed
+                    }
+                };
+                if keep {
+                    let len = write_record(&mut writer, &transaction)?;
+                    hints.push(Hint::new(&transaction, output_pos, len));
+                    output_pos += len;
+                }
+                pos += len;
+            }
+        }
+
+        // Make the output durable before it takes its final name,
+        // and the name durable before the manifest lists it.
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        if hints.is_empty() {
+            remove_file(&temp_path)?;
+            return Ok(None);
+        }
+        fs::rename(&temp_path, self.file_path(output))?;
+        File::open(&self.folder_path)?.sync_all()?;
+        // The output is sealed from the start; its hint only ever exists next to it.
+        write_hint_file_or_warn(&self.folder_path, output, output_pos, &hints);
+        Ok(Some(CompactionOutput {
+            moved,
+            tombstone_bytes,
+        }))
+    }
+
+    /// The keys the candidates remove that still have a value in a file older than `output`
+    /// that isn't being compacted. Only the keys of the tombstones are collected, so the
+    /// other files are just searched for them.
+    fn shadowed_keys(&self, candidates: &[u32], output: u32) -> Result<HashSet<String>> {
+        let version = self.version.load_full();
+        // A key written again since has no use for its tombstone
+        let mut removed = HashSet::new();
+        for &file_index in candidates {
+            removed.extend(
+                self.file_hints(file_index)?
+                    .into_iter()
+                    .filter(|hint| hint.removed && !version.index.contains_key(&hint.key))
+                    .map(|hint| hint.key),
+            );
+        }
+
+        let mut shadowed = HashSet::new();
+        for &file_index in version.files.keys() {
+            if removed.is_empty() || file_index >= output {
+                break;
+            }
+            if candidates.contains(&file_index) {
+                continue;
+            }
+            for hint in self.file_hints(file_index)? {
+                if !hint.removed && removed.remove(&hint.key) {
+                    shadowed.insert(hint.key);
+                }
+            }
+        }
+        Ok(shadowed)
+    }
+
+    /// The hints of the records in a sealed log file, or none if it was never written to
+    fn file_hints(&self, file_index: u32) -> Result<Vec<Hint>> {
+        let path = self.file_path(file_index);
+        let file_len = match fs::metadata(&path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        // Sealed files usually have a hint file.
+        match read_hint_file(&self.folder_path, file_index, file_len)? {
+            Some(hints) => Ok(hints),
+            None => Ok(replay_log_file(&path, file_index)?.0),
+        }
+    }
+
+    /// Point the index at the copied records and retire the candidate files.
+    /// Keys written while the compaction ran keep their newer location.
+    /// Storing the manifest commits the compaction; a crash before that leaves the
+    /// candidates live and the output a leftover, a crash after it the other way around.
+    fn finish_compaction(
+        &self,
+        candidates: &[u32],
+        output: u32,
+        compacted: Option<CompactionOutput>,
+    ) -> Result<()> {
+        let mut writer = self.writer();
+        let mut version = Version::clone(&self.version.load());
+        let mut files = writer.files.clone();
+
+        if let Some(compacted) = compacted {
+            // Values overwritten or removed while the compaction ran are stale in the output.
+            // The tombstones it kept still shadow older values, so they aren't garbage.
+            let mut stats = FileStats {
+                total_bytes: compacted.tombstone_bytes,
+                stale_bytes: 0,
+            };
+            for MovedRecord { key, from, pos } in compacted.moved {
+                let (_, _, len) = from;
+                stats.total_bytes += len;
+                if version.index.get(&key) == Some(&from) {
+                    version.index.insert(key, (output, pos, len));
+                } else {
+                    stats.stale_bytes += len;
+                }
+            }
+            files.insert(output, stats);
+            version
+                .files
+                .insert(output, LogFile::open(&self.file_path(output))?);
+        }
+        for file_index in candidates {
+            files.remove(file_index);
+        }
+        store_manifest(&self.folder_path, files.keys().copied(), writer.active)?;
+        writer.files = files;
+
+        // The compacted files are deleted once the last reader using an older version is done.
+        let retired = Arc::new(RetiredFiles {
+            paths: candidates
+                .iter()
+                .flat_map(|&i| [hint_file_path(&self.folder_path, i), self.file_path(i)])
+                .collect(),
+        });
+        for file_index in candidates {
+            if let Some(file) = version.files.remove(file_index) {
+                let _ = file.retired.set(Arc::clone(&retired));
+            }
+        }
+        self.version.store(Arc::new(version));
+        self.compactions.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn file_path(&self, file_index: u32) -> PathBuf {
+        log_file_path(&self.folder_path, file_index)
+    }
+
+    fn temp_file_path(&self, file_index: u32) -> PathBuf {
+        temp_file_path(&self.folder_path, file_index)
+    }
+}
+
+/// Record the live log files and the active file in the manifest
+fn store_manifest(
+    folder_path: &Path,
+    files: impl IntoIterator<Item = u32>,
+    active: u32,
+) -> Result<()> {
+    Manifest {
+        format_version: FORMAT_VERSION,
+        engine: ENGINE.to_owned(),
+        files: files.into_iter().collect(),
+        active,
+    }
+    .store(folder_path)
+}
+
+/// Read every record of a log file, returning their hints
+/// and the length of the file up to the end of the last complete record
+fn replay_log_file(path: &Path, file_index: u32) -> Result<(Vec<Hint>, u64)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hints = Vec::new();
+    // A file cut short inside its header has no records, so all of it is discarded.
+    if !check_log_header(&mut reader, file_index)? {
+        return Ok((hints, 0));
+    }
+    let mut pos = LOG_HEADER_LEN;
+    while let Some((transaction, len)) = read_record(&mut reader, file_index, pos)? {
+        hints.push(Hint::new(&transaction, pos, len));
+        pos += len;
+    }
+    Ok((hints, pos))
+}
+
+/// Check that a log file starts with the header of the current format.
+/// Returns `false` if the file ends before its header does.
+fn check_log_header<R: Read>(reader: &mut R, file_index: u32) -> Result<bool> {
+    match read_log_header(reader)? {
+        LogHeader::Incomplete => Ok(false),
+        LogHeader::Version(FORMAT_VERSION) => Ok(true),
+        LogHeader::Version(version) => Err(CustomError::IncompatibleStore(format!(
+            "log file {} uses format version {}, this build reads version {}",
+            file_index, version, FORMAT_VERSION
+        ))),
+        LogHeader::Absent => Err(CustomError::Corrupted {
+            file: file_index,
+            offset: 0,
+        }),
+    }
+}
+
+/// The error for a directory written in an older format, which `KvStore::upgrade` migrates
+fn needs_upgrade(written_in: &str) -> CustomError {
+    CustomError::IncompatibleStore(format!(
+        "the directory was written in {}; run `kvs upgrade` to migrate it to format version {}",
+        written_in, FORMAT_VERSION
+    ))
+}
+
+/// Write a hint file; without one, `open` replays the log file instead, so failing is not fatal
+fn write_hint_file_or_warn(folder_path: &Path, file_index: u32, log_len: u64, hints: &[Hint]) {
+    if let Err(e) = write_hint_file(folder_path, file_index, log_len, hints) {
+        warn!(file = file_index, error:% = e; "Failed to write hint file");
+    }
+}
+
+/// Read the record the index points at
+fn read_record_at(file: &File, file_index: u32, offset: u64) -> Result<Transaction> {
+    let mut reader = PositionalReader { file, pos: offset };
+    match read_record(&mut reader, file_index, offset)? {
+        Some((transaction, _)) => Ok(transaction),
+        // The index points past the last complete record
+        None => Err(CustomError::Corrupted {
+            file: file_index,
+            offset,
+        }),
+    }
+}
+
+fn log_file_path(folder_path: &Path, file_index: u32) -> PathBuf {
+    folder_path.join(format!("{}.{}", file_index, LOG_EXTENSION))
+}
+
+/// The file a log file is written to before it is renamed to the log file `file_index`
+fn temp_file_path(folder_path: &Path, file_index: u32) -> PathBuf {
+    folder_path.join(format!("{}.{}", file_index, TEMP_EXTENSION))
+}
+
+impl LogFile {
+    /// A log file that doesn't exist yet
+    fn new() -> Arc<LogFile> {
+        Arc::new(LogFile {
+            file: OnceLock::new(),
+            retired: OnceLock::new(),
+        })
+    }
+
+    /// Open the log file at `path` for reads
+    fn open(path: &Path) -> Result<Arc<LogFile>> {
+        let log_file = LogFile::new();
+        let _ = log_file.file.set(Arc::new(File::open(path)?));
+        Ok(log_file)
+    }
+}
+
+impl Read for PositionalReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.file.read_at(buf, self.pos)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Drop for RetiredFiles {
+    /// Files go in ascending order, so a crash halfway never leaves an old value behind
+    /// without the newer file that overwrote or removed it.
+    fn drop(&mut self) {
+        for path in &self.paths {
+            match remove_file(path) {
+                Ok(()) => {}
+                // Not every log file has a hint file
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    error!(path:? = path, error:% = e; "Failed to delete compacted file");
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ManualCompaction<'_> {
+    fn drop(&mut self) {
+        self.shared.writer().compacting = false;
+    }
+}
+
+impl Drop for StoreHandle {
+    fn drop(&mut self) {
+        let compaction = self.shared.writer().compaction.take();
+        if let Some(compaction) = compaction {
+            let _ = compaction.join();
+        }
+    }
+}
+
+impl KvsEngine for KvStore {
+    type Scan = Scan;
+
+    fn open(path: impl AsRef<Path>) -> Result<Self> {
+        KvStore::open(path)
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        KvStore::set(self, key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        KvStore::get(self, key)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        KvStore::keys(self)
+    }
+
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Scan {
+        KvStore::scan(self, range)
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> Scan {
+        KvStore::scan_prefix(self, prefix)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        KvStore::remove(self, key)
+    }
+
+    /// Every write goes straight to the log file, so flushing only asks the OS
+    /// to push the active file to disk.
+    fn flush(&self) -> Result<()> {
+        self.shared.flush(&mut self.shared.writer())
+    }
+
+    fn compact(&self) -> Result<CompactionReport> {
+        KvStore::compact(self)
+    }
+}
@@ -0,0 +1,104 @@
+use super::policy::{CompactionPolicy, GarbageRatioPolicy};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// When `KvStore` asks the OS to push written records to disk.
+/// Whatever the policy, `flush` always syncs everything written so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// Leave it to the OS until the active file is sealed; a crash of the machine may lose
+    /// recent writes
+    #[default]
+    Never,
+    /// Sync every write before it returns
+    Always,
+    /// Sync once every this many writes
+    EveryN(u32),
+    /// Sync on the first write after this much time has passed since the last sync
+    Interval(Duration),
+    /// Sync every write before it returns, but let writers that arrive while a sync
+    /// is running share the next one
+    GroupCommit,
+}
+
+/// Options for opening a `KvStore`
+/// # Examples
+/// ```
+/// use kvs::{KvStore, KvStoreOptions, SyncPolicy};
+/// let options = KvStoreOptions::new()
+///     .sync_policy(SyncPolicy::GroupCommit)
+///     .max_file_size(4 * 1024 * 1024)
+///     .garbage_ratio(0.3);
+/// let store = KvStore::open_with(std::env::current_dir()?, options)?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    pub(super) sync_policy: SyncPolicy,
+    /// Set by `KvStore::open_read_only`
+    pub(super) read_only: bool,
+    pub(super) max_file_size: u64,
+    /// The settings of the default compaction policy
+    pub(super) garbage_policy: GarbageRatioPolicy,
+    /// The compaction policy that replaces the default one
+    pub(super) custom_policy: Option<Arc<dyn CompactionPolicy>>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> KvStoreOptions {
+        KvStoreOptions {
+            sync_policy: SyncPolicy::default(),
+            read_only: false,
+            max_file_size: 1024 * 1024,
+            garbage_policy: GarbageRatioPolicy::default(),
+            custom_policy: None,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// The default options
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions::default()
+    }
+
+    /// Set when written records are synced to disk, `SyncPolicy::Never` by default
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> KvStoreOptions {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    /// Set the size at which the active file is sealed and writes move on to a new file,
+    /// 1 MiB by default. Compactions are only considered when this happens.
+    pub fn max_file_size(mut self, max_file_size: u64) -> KvStoreOptions {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    /// Set the share of stale bytes at which the default policy compacts a file, 0.5 by default
+    pub fn garbage_ratio(mut self, garbage_ratio: f64) -> KvStoreOptions {
+        self.garbage_policy.garbage_ratio = garbage_ratio;
+        self
+    }
+
+    /// Set the total size of the log files above which the default policy compacts every
+    /// file with garbage, whatever its ratio. Unlimited by default.
+    pub fn max_disk_usage(mut self, max_disk_usage: u64) -> KvStoreOptions {
+        self.garbage_policy.max_disk_usage = Some(max_disk_usage);
+        self
+    }
+
+    /// Decide what to compact with another policy than `GarbageRatioPolicy`.
+    /// The `garbage_ratio` and `max_disk_usage` settings only apply to the default policy.
+    pub fn compaction_policy<P: CompactionPolicy + 'static>(mut self, policy: P) -> KvStoreOptions {
+        self.custom_policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// The compaction policy these options ask for
+    pub(super) fn policy(&self) -> Arc<dyn CompactionPolicy> {
+        match &self.custom_policy {
+            Some(policy) => Arc::clone(policy),
+            None => Arc::new(self.garbage_policy.clone()),
+        }
+    }
+}
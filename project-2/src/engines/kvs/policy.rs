@@ -0,0 +1,70 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+
+/// How much of a log file is garbage
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileStats {
+    /// The bytes of all the records in the file
+    pub total_bytes: u64,
+    /// The bytes of the records that are no longer needed: overwritten or removed values,
+    /// and tombstones. A tombstone that compaction had to keep, or that still shadows a value
+    /// in an older file when the store is opened, is not counted.
+    pub stale_bytes: u64,
+}
+
+impl FileStats {
+    /// The share of the file that is stale, from 0 to 1
+    pub fn garbage_ratio(&self) -> f64 {
+        match self.total_bytes {
+            0 => 0.0,
+            total_bytes => self.stale_bytes as f64 / total_bytes as f64,
+        }
+    }
+}
+
+/// Decides which log files a compaction rewrites.
+/// `KvStore` asks it every time the active file fills up.
+pub trait CompactionPolicy: Debug + Send + Sync {
+    /// Pick the files to compact out of `files`, which are all sealed by the time the
+    /// compaction runs; `disk_usage` is the size of all log files together.
+    /// Returning no files skips the compaction.
+    fn select(&self, files: &BTreeMap<u32, FileStats>, disk_usage: u64) -> Vec<u32>;
+}
+
+/// The default policy: compact the files whose garbage ratio reaches a threshold, and every
+/// file with any garbage once the log files take up more than a given size.
+///
+/// A low ratio keeps the store small at the cost of rewriting more, a high one suits
+/// write-heavy workloads.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GarbageRatioPolicy {
+    /// The share of stale bytes at which a file is compacted
+    pub garbage_ratio: f64,
+    /// The total size of the log files above which every file with garbage is compacted
+    pub max_disk_usage: Option<u64>,
+}
+
+impl Default for GarbageRatioPolicy {
+    fn default() -> GarbageRatioPolicy {
+        GarbageRatioPolicy {
+            garbage_ratio: 0.5,
+            max_disk_usage: None,
+        }
+    }
+}
+
+impl CompactionPolicy for GarbageRatioPolicy {
+    fn select(&self, files: &BTreeMap<u32, FileStats>, disk_usage: u64) -> Vec<u32> {
+        let over_budget = self
+            .max_disk_usage
+            .is_some_and(|max_disk_usage| disk_usage > max_disk_usage);
+        files
+            .iter()
+            .filter(|(_, stats)| match over_budget {
+                true => stats.stale_bytes > 0,
+                false => stats.stale_bytes > 0 && stats.garbage_ratio() >= self.garbage_ratio,
+            })
+            .map(|(&file_index, _)| file_index)
+            .collect()
+    }
+}
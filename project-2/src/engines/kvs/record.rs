@@ -0,0 +1,130 @@
+//! The framing of the records in the log and hint files.
+//!
+//! A log file starts with a header: the magic number `KVSL` and the format version
+//! as a u32 LE. Every record is `[len: u32 LE][crc: u32 LE][header crc: u32 LE][payload]`,
+//! where the payload is the bincode encoding of the record (a `Transaction` in log files),
+//! `crc` is the CRC32C of the payload and `header crc` the CRC32C of `len` and `crc`.
+//!
+//! The header checksum tells a damaged length from a record cut short by a crash: without it,
+//! a flipped bit in a length could pass for a record that runs past the end of the file.
+use crate::error::{CustomError, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+
+/// The length of the record header: the payload length, its checksum and the header checksum
+const HEADER_LEN: usize = 12;
+/// The version of the on-disk format: log file headers, framed records and the MANIFEST
+pub(super) const FORMAT_VERSION: u32 = 2;
+/// The magic number every log file starts with
+const LOG_MAGIC: [u8; 4] = *b"KVSL";
+/// The length of the log file header, so the offset of the first record
+pub(super) const LOG_HEADER_LEN: u64 = 8;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) enum Transaction {
+    Set(String, String),
+    Remove(String),
+}
+
+/// Write one record, returning its length in bytes
+pub(super) fn write_record<W: Write, T: Serialize>(writer: &mut W, record: &T) -> Result<u64> {
+    let payload = bincode::serialize(record)?;
+    let len = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record too large"))?;
+
+    let mut framed = Vec::with_capacity(HEADER_LEN + payload.len());
+    framed.extend_from_slice(&len.to_le_bytes());
+    framed.extend_from_slice(&crc32c::crc32c(&payload).to_le_bytes());
+    let header_crc = crc32c::crc32c(&framed);
+    framed.extend_from_slice(&header_crc.to_le_bytes());
+    framed.extend_from_slice(&payload);
+    // One write, so concurrent readers of the file never see a header without its payload
+    writer.write_all(&framed)?;
+    Ok(framed.len() as u64)
+}
+
+/// Read the record at `offset` in the file of `file_index`, returning it with its length in bytes.
+/// Returns `None` at the end of the file, including when the last record is cut short;
+/// a caller that needs to tell the two apart compares the offset to the file length.
+/// A record whose header fails its checksum is `CustomError::Corrupted`, even at the end.
+pub(super) fn read_record<R: Read, T: DeserializeOwned>(
+    reader: &mut R,
+    file_index: u32,
+    offset: u64,
+) -> Result<Option<(T, u64)>> {
+    let mut header = [0u8; HEADER_LEN];
+    if read_full(reader, &mut header)? < HEADER_LEN {
+        return Ok(None);
+    }
+    let corrupted = || CustomError::Corrupted {
+        file: file_index,
+        offset,
+    };
+    let header_crc = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+    if crc32c::crc32c(&header[..8]) != header_crc {
+        return Err(corrupted());
+    }
+    let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+
+    // Grown while reading, so a corrupted length can't make us allocate gigabytes up front
+    let mut payload = Vec::new();
+    reader.take(u64::from(len)).read_to_end(&mut payload)?;
+    if payload.len() < len as usize {
+        return Ok(None);
+    }
+
+    if crc32c::crc32c(&payload) != crc {
+        return Err(corrupted());
+    }
+    let record = bincode::deserialize(&payload).map_err(|_| corrupted())?;
+    Ok(Some((record, (HEADER_LEN + payload.len()) as u64)))
+}
+
+/// Write the header a log file starts with
+pub(super) fn write_log_header<W: Write>(writer: &mut W) -> Result<()> {
+    let mut header = [0u8; LOG_HEADER_LEN as usize];
+    header[..4].copy_from_slice(&LOG_MAGIC);
+    header[4..].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+    writer.write_all(&header)?;
+    Ok(())
+}
+
+/// What a log file starts with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum LogHeader {
+    /// The file ends before the header does, like a file created just before a crash
+    Incomplete,
+    /// The file starts with something else, like log files written in format 1
+    Absent,
+    /// The format version in the header
+    Version(u32),
+}
+
+/// Read the header of a log file
+pub(super) fn read_log_header<R: Read>(reader: &mut R) -> Result<LogHeader> {
+    let mut header = [0u8; LOG_HEADER_LEN as usize];
+    if read_full(reader, &mut header)? < header.len() {
+        return Ok(LogHeader::Incomplete);
+    }
+    if header[..4] != LOG_MAGIC {
+        return Ok(LogHeader::Absent);
+    }
+    let version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    Ok(LogHeader::Version(version))
+}
+
+/// Fill `buf` as far as the reader goes, returning how many bytes were read
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
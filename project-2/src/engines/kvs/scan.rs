@@ -0,0 +1,79 @@
+//! Iteration over the key/value pairs of a `KvStore` in key order.
+use super::snapshot::Snapshot;
+use crate::error::Result;
+use std::ops::Bound;
+
+/// An iterator over the key/value pairs of a `KvStore` whose keys fall in a range, in
+/// ascending key order. `rev` walks it from the last key, and `take` limits it.
+/// Created by the `scan` and `scan_prefix` methods of `KvStore` and `Snapshot`.
+///
+/// It reads from a snapshot, so writes made while it runs don't show up in it.
+/// Values are read from disk as the iterator advances.
+pub struct Scan {
+    snapshot: Snapshot,
+    /// The bounds of the keys that weren't returned yet, from either end
+    lower: Bound<String>,
+    upper: Bound<String>,
+}
+
+impl Scan {
+    pub(super) fn new(snapshot: Snapshot, lower: Bound<String>, upper: Bound<String>) -> Scan {
+        Scan {
+            snapshot,
+            lower,
+            upper,
+        }
+    }
+
+    /// The keys left, unless the bounds have met
+    fn remaining(&self) -> Option<(Bound<&str>, Bound<&str>)> {
+        let lower = self.lower.as_ref().map(String::as_str);
+        let upper = self.upper.as_ref().map(String::as_str);
+        let is_empty = match (lower, upper) {
+            (Bound::Included(lower), Bound::Included(upper)) => lower > upper,
+            (Bound::Included(lower) | Bound::Excluded(lower), Bound::Excluded(upper))
+            | (Bound::Excluded(lower), Bound::Included(upper)) => lower >= upper,
+            _ => false,
+        };
+        (!is_empty).then_some((lower, upper))
+    }
+
+    fn read(
+        &self,
+        key: String,
+        (file_index, offset, _): (u32, u64, u64),
+    ) -> Result<(String, String)> {
+        let value = self.snapshot.read_value(&key, file_index, offset)?;
+        Ok((key, value))
+    }
+}
+
+impl Iterator for Scan {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, &location) = self
+            .snapshot
+            .version()
+            .index
+            .range::<_, str>(self.remaining()?)
+            .next()?;
+        let key = key.clone();
+        self.lower = Bound::Excluded(key.clone());
+        Some(self.read(key, location))
+    }
+}
+
+impl DoubleEndedIterator for Scan {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let (key, &location) = self
+            .snapshot
+            .version()
+            .index
+            .range::<_, str>(self.remaining()?)
+            .next_back()?;
+        let key = key.clone();
+        self.upper = Bound::Excluded(key.clone());
+        Some(self.read(key, location))
+    }
+}
@@ -0,0 +1,66 @@
+//! Point-in-time views of a `KvStore`.
+use super::scan::Scan;
+use super::{prefix_range, KvStore, Version};
+use crate::error::Result;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+
+/// A read-only view of a `KvStore` as it was at one point in time, created by
+/// `KvStore::snapshot`.
+///
+/// Reads through a snapshot see exactly the writes up to its sequence number, whatever is
+/// written or compacted afterwards. The log files it reads from stay on disk until it is
+/// dropped. A snapshot is a handle to the store like a clone of it.
+#[derive(Clone)]
+pub struct Snapshot {
+    store: KvStore,
+    version: Arc<Version>,
+}
+
+impl Snapshot {
+    pub(super) fn new(store: KvStore, version: Arc<Version>) -> Snapshot {
+        Snapshot { store, version }
+    }
+
+    /// The sequence number of the last write the snapshot sees.
+    /// Writes are numbered from 1 every time the store is opened.
+    pub fn seq(&self) -> u64 {
+        self.version.seq
+    }
+
+    /// Get the value a key had when the snapshot was taken.
+    /// Returns `None` if the key did not exist.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.store.get_in(&self.version, key)
+    }
+
+    /// List every key the store had when the snapshot was taken, in ascending order.
+    pub fn keys(&self) -> Result<Vec<String>> {
+        Ok(self.version.index.keys().cloned().collect())
+    }
+
+    /// Iterate over the key/value pairs whose keys fall in `range`, in ascending key order
+    pub fn scan<R: RangeBounds<String>>(&self, range: R) -> Scan {
+        Scan::new(
+            self.clone(),
+            range.start_bound().cloned(),
+            range.end_bound().cloned(),
+        )
+    }
+
+    /// Iterate over the key/value pairs whose keys start with `prefix`, in ascending key order
+    pub fn scan_prefix(&self, prefix: &str) -> Scan {
+        self.scan(prefix_range(prefix))
+    }
+
+    /// The version the snapshot is pinned to
+    pub(super) fn version(&self) -> &Version {
+        &self.version
+    }
+
+    /// Read the value of `key` from where the index of the snapshot points
+    pub(super) fn read_value(&self, key: &str, file_index: u32, offset: u64) -> Result<String> {
+        self.store
+            .read_value(&self.version, key, file_index, offset)
+    }
+}
@@ -0,0 +1,218 @@
+//! Migration of store directories written in older on-disk formats.
+//!
+//! - Format 1 stores bare bincode `Transaction`s back to back, without a manifest.
+//! - Format 2 starts every log file with a header holding a magic number and the version,
+//!   frames every record with its length and CRC32C checksums of the payload and the header,
+//!   and adds hint files and the MANIFEST.
+//!
+//! Directories without a manifest hold format 1.
+//!
+//! An upgrade writes the live values to one new log file and then stores a current manifest
+//! that only lists that file. Storing the manifest commits the upgrade: a crash before it
+//! leaves the old store as it was, and `open` deletes the old files after a crash following it.
+use super::hint::{Hint, HINT_EXTENSION};
+use super::record::{
+    read_log_header, read_record, write_log_header, write_record, LogHeader, Transaction,
+    FORMAT_VERSION, LOG_HEADER_LEN,
+};
+use super::{
+    log_file_path, store_manifest, temp_file_path, write_hint_file_or_warn, ENGINE, LOG_EXTENSION,
+};
+use crate::engines::lock::DirLock;
+use crate::engines::manifest::Manifest;
+use crate::error::{CustomError, Result};
+use log::info;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::{self, remove_file, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+
+/// Migrate the store in `folder_path` to the current format, in place
+pub(super) fn upgrade(folder_path: &Path) -> Result<u32> {
+    let _lock = DirLock::acquire(folder_path)?;
+    let (format_version, files) = find_log_files(folder_path)?;
+    if format_version == FORMAT_VERSION {
+        return Ok(FORMAT_VERSION);
+    }
+    let values = read_values(folder_path, format_version, &files)?;
+    let output = files.last().map_or(0, |last| last + 1);
+    write_store(folder_path, output, &values)?;
+
+    // The new manifest only lists the output, so everything else is garbage now.
+    for entry in fs::read_dir(folder_path)? {
+        let path = entry?.path();
+        let extension = path.extension();
+        let is_store_file = extension == Some(OsStr::new(LOG_EXTENSION))
+            || extension == Some(OsStr::new(HINT_EXTENSION));
+        if is_store_file && path.file_stem() != Some(OsStr::new(&output.to_string())) {
+            remove_file(&path)?;
+        }
+    }
+    info!(from = format_version, to = FORMAT_VERSION, keys = values.len(); "Upgraded store");
+    Ok(format_version)
+}
+
+/// Write the store in `from` to the new store `to` in the current format, leaving `from` as it is
+pub(super) fn upgrade_into(from: &Path, to: &Path) -> Result<u32> {
+    fs::create_dir_all(to)?;
+    let _lock = DirLock::acquire(to)?;
+    let (_, existing) = find_log_files(to)?;
+    if !existing.is_empty() || Manifest::load(to)?.is_some() {
+        return Err(CustomError::IncompatibleStore(format!(
+            "{} already holds a store",
+            to.display()
+        )));
+    }
+
+    let (format_version, files) = find_log_files(from)?;
+    let values = read_values(from, format_version, &files)?;
+    write_store(to, 0, &values)?;
+    info!(from = format_version, to = FORMAT_VERSION, keys = values.len(); "Upgraded store");
+    Ok(format_version)
+}
+
+/// Find the format version of a store and its log files, in ascending order.
+/// A directory without log files counts as the current format.
+fn find_log_files(folder_path: &Path) -> Result<(u32, Vec<u32>)> {
+    if let Some(manifest) = Manifest::load(folder_path)? {
+        manifest.check_engine(ENGINE)?;
+        if manifest.format_version > FORMAT_VERSION {
+            return Err(CustomError::IncompatibleStore(format!(
+                "the directory uses format version {}, which is newer than this build",
+                manifest.format_version
+            )));
+        }
+        // The active file is only created by its first write
+        let files = manifest
+            .files
+            .iter()
+            .copied()
+            .filter(|&file_index| log_file_path(folder_path, file_index).exists())
+            .collect();
+        return Ok((manifest.format_version, files));
+    }
+
+    let mut files = Vec::new();
+    for entry in fs::read_dir(folder_path)? {
+        let path = entry?.path();
+        if path.extension() != Some(OsStr::new(LOG_EXTENSION)) {
+            continue;
+        }
+        let Some(file_index) = path
+            .file_stem()
+            .and_then(|stem| stem.to_string_lossy().parse::<u32>().ok())
+        else {
+            continue;
+        };
+        // A file with a header is the output of an upgrade that crashed before its manifest
+        if read_log_header(&mut File::open(&path)?)? == LogHeader::Absent {
+            files.push(file_index);
+        }
+    }
+    files.sort_unstable();
+    match files.is_empty() {
+        true => Ok((FORMAT_VERSION, files)),
+        false => Ok((1, files)),
+    }
+}
+
+/// Replay the log files of a store, returning the live values
+fn read_values(
+    folder_path: &Path,
+    format_version: u32,
+    files: &[u32],
+) -> Result<BTreeMap<String, String>> {
+    let mut values = BTreeMap::new();
+    for &file_index in files {
+        for transaction in read_transactions(folder_path, format_version, file_index)? {
+            match transaction {
+                Transaction::Set(key, value) => values.insert(key, value),
+                Transaction::Remove(key) => values.remove(&key),
+            };
+        }
+    }
+    Ok(values)
+}
+
+/// Read the transactions of a log file written in `format_version`, in order.
+/// An incomplete record at the end of the file is left out, like `open` does.
+fn read_transactions(
+    folder_path: &Path,
+    format_version: u32,
+    file_index: u32,
+) -> Result<Vec<Transaction>> {
+    let mut reader = BufReader::new(File::open(log_file_path(folder_path, file_index))?);
+    if format_version == 1 {
+        return read_bare_transactions(&mut reader, file_index);
+    }
+    let mut transactions = Vec::new();
+    let mut pos = match read_log_header(&mut reader)? {
+        LogHeader::Incomplete => return Ok(transactions),
+        LogHeader::Version(version) if version == format_version => LOG_HEADER_LEN,
+        _ => {
+            return Err(CustomError::Corrupted {
+                file: file_index,
+                offset: 0,
+            })
+        }
+    };
+    while let Some((transaction, len)) = read_record(&mut reader, file_index, pos)? {
+        transactions.push(transaction);
+        pos += len;
+    }
+    Ok(transactions)
+}
+
+/// Read the bincode `Transaction`s of a format 1 log file
+fn read_bare_transactions(
+    reader: &mut BufReader<File>,
+    file_index: u32,
+) -> Result<Vec<Transaction>> {
+    let mut transactions = Vec::new();
+    let mut pos = 0;
+    loop {
+        match bincode::deserialize_from::<_, Transaction>(&mut *reader) {
+            Ok(transaction) => {
+                pos += bincode::serialized_size(&transaction)?;
+                transactions.push(transaction);
+            }
+            Err(e) => match *e {
+                bincode::ErrorKind::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(transactions)
+                }
+                _ => {
+                    return Err(CustomError::Corrupted {
+                        file: file_index,
+                        offset: pos,
+                    })
+                }
+            },
+        }
+    }
+}
+
+/// Write `values` to the sealed log file `output` and commit it with a manifest
+fn write_store(folder_path: &Path, output: u32, values: &BTreeMap<String, String>) -> Result<()> {
+    let temp_path = temp_file_path(folder_path, output);
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+    write_log_header(&mut writer)?;
+    let mut pos = LOG_HEADER_LEN;
+    let mut hints = Vec::with_capacity(values.len());
+    for (key, value) in values {
+        let transaction = Transaction::Set(key.clone(), value.clone());
+        let len = write_record(&mut writer, &transaction)?;
+        hints.push(Hint::new(&transaction, pos, len));
+        pos += len;
+    }
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    fs::rename(&temp_path, log_file_path(folder_path, output))?;
+    File::open(folder_path)?.sync_all()?;
+    write_hint_file_or_warn(folder_path, output, pos, &hints);
+
+    // New records go to the next file
+    store_manifest(folder_path, [output, output + 1], output + 1)
+}
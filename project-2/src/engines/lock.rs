@@ -0,0 +1,29 @@
+//! The LOCK file, which keeps a second process from writing to a store directory.
+use crate::error::{CustomError, Result};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::Path;
+
+const LOCK_FILE: &str = "LOCK";
+
+/// An exclusive advisory lock on a store directory, released when dropped.
+/// The OS also releases it when the process dies, so a crash never leaves a stale lock.
+#[derive(Debug)]
+pub(crate) struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    /// Lock the directory, failing with `CustomError::Locked` if another handle holds it
+    pub(crate) fn acquire(folder_path: &Path) -> Result<DirLock> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(folder_path.join(LOCK_FILE))?;
+        match file.try_lock() {
+            Ok(()) => Ok(DirLock { _file: file }),
+            Err(TryLockError::WouldBlock) => Err(CustomError::Locked(folder_path.to_path_buf())),
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+}
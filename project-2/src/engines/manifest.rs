@@ -0,0 +1,72 @@
+//! The MANIFEST file, which records what a store directory holds.
+use crate::error::{CustomError, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+
+const MANIFEST_FILE: &str = "MANIFEST";
+
+/// The contents of the MANIFEST file, stored as JSON
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Manifest {
+    /// The version of the on-disk format of the files
+    pub(crate) format_version: u32,
+    /// The engine that owns the directory
+    pub(crate) engine: String,
+    /// The live log files, in ascending order
+    pub(crate) files: Vec<u32>,
+    /// The log file new records are appended to
+    pub(crate) active: u32,
+}
+
+impl Manifest {
+    /// Read the manifest of a directory, if it has one
+    pub(crate) fn load(folder_path: &Path) -> Result<Option<Manifest>> {
+        match File::open(folder_path.join(MANIFEST_FILE)) {
+            Ok(file) => Ok(Some(serde_json::from_reader(BufReader::new(file))?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replace the manifest of a directory.
+    /// The new manifest is written to a temporary file, synced and renamed over the old one,
+    /// so a crash leaves either the old or the new manifest behind.
+    pub(crate) fn store(&self, folder_path: &Path) -> Result<()> {
+        let path = folder_path.join(MANIFEST_FILE);
+        let temp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        fs::rename(&temp_path, &path)?;
+        File::open(folder_path)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Refuse a directory that belongs to another engine
+    pub(crate) fn check_engine(&self, engine: &str) -> Result<()> {
+        if self.engine != engine {
+            return Err(CustomError::IncompatibleStore(format!(
+                "the directory holds a `{}` store, not a `{}` store",
+                self.engine, engine
+            )));
+        }
+        Ok(())
+    }
+
+    /// Refuse a directory that belongs to another engine or uses another format
+    pub(crate) fn check(&self, engine: &str, format_version: u32) -> Result<()> {
+        self.check_engine(engine)?;
+        if self.format_version != format_version {
+            return Err(CustomError::IncompatibleStore(format!(
+                "the directory uses format version {}, this build reads version {}",
+                self.format_version, format_version
+            )));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,216 @@
+use super::manifest::Manifest;
+use super::{prefix_range, KvsEngine};
+use crate::error::{CustomError, Result};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, Write};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::vec;
+
+const SNAPSHOT_FILE: &str = "memory.json";
+/// The engine name in the MANIFEST
+const ENGINE: &str = "memory";
+/// The version of the snapshot format
+const FORMAT_VERSION: u32 = 1;
+
+/// The in-memory Key Value Store, which uses a HashMap underneath.
+/// The whole map is written to a single JSON snapshot on `flush` (and when the
+/// last handle is dropped), and read back on `open`.
+///
+/// Clones share the same map; readers only wait for a write in progress.
+/// # Examples
+/// ```
+/// use kvs::{KvsEngine, MemoryStore};
+/// let store = MemoryStore::open(std::env::current_dir()?)?;
+/// store.set("key".to_string(), "value".to_string())?;
+/// ```
+#[derive(Clone)]
+pub struct MemoryStore {
+    inner: Arc<MemoryStoreInner>,
+}
+
+/// The state shared by every clone of a `MemoryStore`
+struct MemoryStoreInner {
+    /// The storage for the key value pairs
+    storage: RwLock<HashMap<String, String>>,
+    /// The folder that the snapshot is stored in
+    folder_path: PathBuf,
+    /// Whether the storage changed since the last snapshot
+    dirty: AtomicBool,
+    /// Held while a snapshot is written, so concurrent flushes don't race on the temporary file
+    flushing: Mutex<()>,
+}
+
+impl MemoryStore {
+    /// Open a Memory Store from a folder, loading the snapshot if there is one
+    pub fn open<F: AsRef<Path>>(path: F) -> Result<MemoryStore> {
+        let folder_path = PathBuf::from(path.as_ref());
+        // The manifest claims the directory, so other engines don't take it for a new store.
+        match Manifest::load(&folder_path)? {
+            Some(manifest) => manifest.check_engine(ENGINE)?,
+            None => Manifest {
+                format_version: FORMAT_VERSION,
+                engine: ENGINE.to_owned(),
+                files: Vec::new(),
+                active: 0,
+            }
+            .store(&folder_path)?,
+        }
+        let snapshot_path = folder_path.join(SNAPSHOT_FILE);
+        let storage = if snapshot_path.exists() {
+            serde_json::from_reader(BufReader::new(File::open(&snapshot_path)?))?
+        } else {
+            HashMap::new()
+        };
+
+        Ok(MemoryStore {
+            inner: Arc::new(MemoryStoreInner {
+                storage: RwLock::new(storage),
+                folder_path,
+                dirty: AtomicBool::new(false),
+                flushing: Mutex::new(()),
+            }),
+        })
+    }
+
+    /// Set a key to a value
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.inner.write().insert(key, value);
+        self.inner.dirty.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Get a value associated with a key
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.inner.read().get(&key).cloned())
+    }
+
+    /// List every key in the store, in ascending order
+    pub fn keys(&self) -> Result<Vec<String>> {
+        let mut keys: Vec<String> = self.inner.read().keys().cloned().collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// The key/value pairs whose keys fall in `range`, in ascending key order.
+    /// They are copied out of the store up front.
+    pub fn scan<R: RangeBounds<String>>(
+        &self,
+        range: R,
+    ) -> vec::IntoIter<Result<(String, String)>> {
+        let mut pairs: Vec<(String, String)> = self
+            .inner
+            .read()
+            .iter()
+            .filter(|(key, _)| range.contains(*key))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        pairs.sort_unstable();
+        pairs.into_iter().map(Ok).collect::<Vec<_>>().into_iter()
+    }
+
+    /// The key/value pairs whose keys start with `prefix`, in ascending key order
+    pub fn scan_prefix(&self, prefix: &str) -> vec::IntoIter<Result<(String, String)>> {
+        self.scan(prefix_range(prefix))
+    }
+
+    /// Remove a key with its value from the store
+    pub fn remove(&self, key: String) -> Result<()> {
+        match self.inner.write().remove(&key) {
+            Some(_) => {
+                self.inner.dirty.store(true, Ordering::SeqCst);
+                Ok(())
+            }
+            None => Err(CustomError::KeyNotFound),
+        }
+    }
+
+    /// Write the snapshot to disk if anything changed.
+    /// The snapshot is written to a temporary file first and then renamed,
+    /// so a crash never leaves a half-written snapshot behind.
+    pub fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl MemoryStoreInner {
+    fn read(&self) -> RwLockReadGuard<'_, HashMap<String, String>> {
+        self.storage.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, HashMap<String, String>> {
+        self.storage.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn flush(&self) -> Result<()> {
+        let _flushing = self.flushing.lock().unwrap_or_else(PoisonError::into_inner);
+        // Writes only wait for the serialization, not for the disk.
+        let snapshot = {
+            let storage = self.read();
+            if !self.dirty.swap(false, Ordering::SeqCst) {
+                return Ok(());
+            }
+            serde_json::to_vec(&*storage)
+        };
+        let snapshot_path = self.folder_path.join(SNAPSHOT_FILE);
+        let temp_path = snapshot_path.with_extension("json.tmp");
+        let written = (|| -> Result<()> {
+            let snapshot = snapshot?;
+            let mut file = File::create(&temp_path)?;
+            file.write_all(&snapshot)?;
+            file.sync_all()?;
+            fs::rename(&temp_path, &snapshot_path)?;
+            Ok(())
+        })();
+        if written.is_err() {
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+        written
+    }
+}
+
+impl Drop for MemoryStoreInner {
+    fn drop(&mut self) {
+        // Errors can't be reported from drop; call `flush` to observe them.
+        let _ = self.flush();
+    }
+}
+
+impl KvsEngine for MemoryStore {
+    type Scan = vec::IntoIter<Result<(String, String)>>;
+
+    fn open(path: impl AsRef<Path>) -> Result<Self> {
+        MemoryStore::open(path)
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        MemoryStore::set(self, key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        MemoryStore::get(self, key)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        MemoryStore::keys(self)
+    }
+
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Self::Scan {
+        MemoryStore::scan(self, range)
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> Self::Scan {
+        MemoryStore::scan_prefix(self, prefix)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        MemoryStore::remove(self, key)
+    }
+
+    fn flush(&self) -> Result<()> {
+        MemoryStore::flush(self)
+    }
+}
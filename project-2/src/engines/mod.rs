@@ -0,0 +1,92 @@
+use crate::Result;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::time::Duration;
+
+mod kvs;
+mod lock;
+mod manifest;
+mod memory;
+
+pub use self::kvs::{
+    CompactionPolicy, FileStats, GarbageRatioPolicy, KvStore, KvStoreOptions, Scan, Snapshot,
+    SyncPolicy,
+};
+pub use self::memory::MemoryStore;
+
+/// The storage interface every Key Value Store backend implements.
+/// Callers that only use this trait can switch backends without code changes.
+///
+/// Engines are cheap handles: cloning one gives another handle to the same store,
+/// which can be moved to another thread and used concurrently with the original.
+pub trait KvsEngine: Clone + Send + Sync + 'static {
+    /// The iterator `scan` returns
+    type Scan: DoubleEndedIterator<Item = Result<(String, String)>>;
+
+    /// Open the store kept in the given folder, loading whatever was persisted there.
+    fn open(path: impl AsRef<Path>) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Set a key to a value, overwriting any previous value.
+    fn set(&self, key: String, value: String) -> Result<()>;
+
+    /// Get the value associated with a key.
+    /// Returns `None` if the key does not exist.
+    fn get(&self, key: String) -> Result<Option<String>>;
+
+    /// List every key in the store, in ascending order.
+    fn keys(&self) -> Result<Vec<String>>;
+
+    /// Iterate over the key/value pairs whose keys fall in `range`, in ascending key order.
+    /// Use `rev` for descending order and `take` to limit the number of pairs.
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Self::Scan;
+
+    /// Iterate over the key/value pairs whose keys start with `prefix`, like `scan`.
+    fn scan_prefix(&self, prefix: &str) -> Self::Scan {
+        self.scan(prefix_range(prefix))
+    }
+
+    /// Remove a key and its associated value from the store.
+    /// Returns `CustomError::KeyNotFound` if the key does not exist.
+    fn remove(&self, key: String) -> Result<()>;
+
+    /// Make sure everything written so far is persisted.
+    fn flush(&self) -> Result<()>;
+
+    /// Reclaim the space taken by overwritten and removed values, and report what was done.
+    /// Engines that have nothing to reclaim do nothing.
+    fn compact(&self) -> Result<CompactionReport> {
+        Ok(CompactionReport::default())
+    }
+}
+
+/// What a compaction did
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionReport {
+    /// The number of files that were rewritten
+    pub files_rewritten: usize,
+    /// The bytes freed on disk, after subtracting what was written.
+    /// Files that a reader still uses are freed later and aren't counted.
+    pub bytes_reclaimed: u64,
+    /// How long the compaction took
+    pub duration: Duration,
+}
+
+/// The range of keys that start with `prefix`: from the prefix itself up to, but excluding,
+/// the first string after every key with the prefix
+fn prefix_range(prefix: &str) -> (Bound<String>, Bound<String>) {
+    let mut end = prefix.to_owned();
+    // Strings compare like their code points, so bump the last one that can be bumped.
+    while let Some(last) = end.pop() {
+        let next = match last {
+            '\u{D7FF}' => Some('\u{E000}'),
+            last => char::from_u32(last as u32 + 1),
+        };
+        if let Some(next) = next {
+            end.push(next);
+            return (Bound::Included(prefix.to_owned()), Bound::Excluded(end));
+        }
+    }
+    (Bound::Included(prefix.to_owned()), Bound::Unbounded)
+}
@@ -3,16 +3,62 @@ use thiserror::Error;
 /// Custom error type
 #[derive(Error, Debug)]
 pub enum CustomError {
+    /// An I/O error
     #[error("Some error occurred")]
     Io(#[from] std::io::Error),
+    /// The key does not exist in the store
     #[error("Key not found")]
     KeyNotFound,
+    /// A JSON (de)serialization error
     #[error("Serde error")]
     Serde(#[from] serde_json::Error),
+    /// A bincode (de)serialization error
     #[error("Bincode error")]
     Bincode(#[from] bincode::Error),
+    /// An error the server reported that has no matching variant
+    #[error("Server error: {0}")]
+    Server(String),
+    /// The peer broke the wire protocol
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+    /// Client and server speak incompatible protocol versions
+    #[error("Incompatible protocol version: client speaks {client}, server speaks {server}")]
+    ProtocolVersion {
+        /// The version the client speaks
+        client: u16,
+        /// The version the server speaks
+        server: u16,
+    },
+    /// The server sent a response that does not fit the request
+    #[error("Unexpected response from the server")]
+    UnexpectedResponse,
+    /// A log record failed its checksum or could not be decoded
+    #[error("Corrupted record in log file {file} at offset {offset}")]
+    Corrupted {
+        /// The number of the log file
+        file: u32,
+        /// The offset of the record in the file
+        offset: u64,
+    },
+    /// The directory holds a store this engine or build can't open
+    #[error("Incompatible store: {0}")]
+    IncompatibleStore(String),
+    /// Another process or handle has the store directory open for writing
+    #[error("Store in {} is locked by another process", .0.display())]
+    Locked(std::path::PathBuf),
+    /// The store was opened read-only, so it can't be written to
+    #[error("The store is open read-only")]
+    ReadOnly,
+    /// A failed write left the active log file in an unknown state, so the store refuses
+    /// further writes until it is opened again
+    #[error("A failed write left the log file in an unknown state; reopen the store")]
+    WriteFailed,
+    /// The index pointed at a log record that does not hold the key
+    #[error("Stale index entry")]
+    StaleIndex,
+    /// Any other boxed error
     #[error("Box<ErrorKind>")]
-    BoxedError(#[from] Box<dyn std::error::Error>),
+    BoxedError(#[from] Box<dyn std::error::Error + Send + Sync>),
 }
 
 /// Type alias
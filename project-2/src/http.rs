@@ -0,0 +1,334 @@
+//! An HTTP/JSON front end, so dashboards and `curl` can use the store.
+//!
+//! - `GET /keys/{key}` returns `{"key": ..., "value": ...}`
+//! - `PUT /keys/{key}` with body `{"value": ...}` sets the key
+//! - `DELETE /keys/{key}` removes the key
+//! - `GET /keys?prefix=...` returns `{"entries": [{"key": ..., "value": ...}, ...]}` in key order
+//!
+//! Errors come back as `{"error": ...}` with a matching status, e.g. 404 for a missing key.
+//! Each connection carries a single request and is closed after the response.
+use crate::error::{CustomError, Result};
+use crate::server::Executor;
+use crate::KvsEngine;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+
+/// The longest request line or header line, in bytes
+const MAX_LINE_LEN: usize = 8 * 1024;
+/// The most headers a request may have
+const MAX_HEADERS: usize = 100;
+/// The largest request body, in bytes
+const MAX_BODY_LEN: usize = 64 * 1024 * 1024;
+
+/// A parsed HTTP request
+struct HttpRequest {
+    method: String,
+    path: String,
+    query: Option<String>,
+    body: Vec<u8>,
+}
+
+/// A response about to be sent; the body is always JSON
+struct HttpResponse {
+    status: u16,
+    body: Option<String>,
+    allow: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+struct Entry {
+    key: String,
+    value: String,
+}
+
+#[derive(Serialize)]
+struct Entries {
+    entries: Vec<Entry>,
+}
+
+#[derive(Deserialize)]
+struct SetBody {
+    value: String,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
+}
+
+impl HttpResponse {
+    fn json<T: Serialize>(status: u16, body: &T) -> HttpResponse {
+        HttpResponse {
+            status,
+            body: Some(serde_json::to_string(body).expect("JSON bodies always serialize")),
+            allow: None,
+        }
+    }
+
+    fn no_content() -> HttpResponse {
+        HttpResponse {
+            status: 204,
+            body: None,
+            allow: None,
+        }
+    }
+
+    fn error(status: u16, message: &str) -> HttpResponse {
+        HttpResponse::json(status, &ErrorBody { error: message })
+    }
+
+    fn method_not_allowed(allow: &'static str) -> HttpResponse {
+        HttpResponse {
+            allow: Some(allow),
+            ..HttpResponse::error(405, "Method not allowed")
+        }
+    }
+
+    /// The response reporting an engine error
+    fn from_engine_error(e: CustomError) -> HttpResponse {
+        match e {
+            CustomError::KeyNotFound => HttpResponse::error(404, &e.to_string()),
+            e => HttpResponse::error(500, &e.to_string()),
+        }
+    }
+}
+
+/// Serve the single request of one HTTP connection
+pub(crate) fn serve<E: KvsEngine>(engine: &E, tcp: TcpStream, executor: &Executor) -> Result<()> {
+    let peer = tcp.peer_addr()?;
+    let mut reader = BufReader::new(&tcp);
+    let mut writer = BufWriter::new(&tcp);
+
+    let response = match read_request(&mut reader, &mut writer) {
+        Ok(Some(request)) => {
+            info!(
+                peer:% = peer,
+                method = request.method.as_str(),
+                path = request.path.as_str();
+                "HTTP request"
+            );
+            let engine = engine.clone();
+            executor.run(move || route(&engine, request))?
+        }
+        // The client connected and hung up without asking anything.
+        Ok(None) => return Ok(()),
+        Err(CustomError::Protocol(message)) => {
+            debug!(peer:% = peer, error = message.as_str(); "Malformed HTTP request");
+            HttpResponse::error(400, &message)
+        }
+        Err(e) => return Err(e),
+    };
+
+    debug!(peer:% = peer, status = response.status; "HTTP response");
+    write_response(&mut writer, &response)
+}
+
+fn route<E: KvsEngine>(engine: &E, request: HttpRequest) -> HttpResponse {
+    if request.path == "/keys" {
+        return match request.method.as_str() {
+            "GET" => list(engine, request.query.as_deref()),
+            _ => HttpResponse::method_not_allowed("GET"),
+        };
+    }
+    let Some(key) = request.path.strip_prefix("/keys/") else {
+        return HttpResponse::error(404, "Not found");
+    };
+    let key = match percent_decode(key, false) {
+        Some(key) if !key.is_empty() => key,
+        _ => return HttpResponse::error(400, "Invalid key"),
+    };
+
+    match request.method.as_str() {
+        "GET" => match engine.get(key.clone()) {
+            Ok(Some(value)) => HttpResponse::json(200, &Entry { key, value }),
+            Ok(None) => HttpResponse::from_engine_error(CustomError::KeyNotFound),
+            Err(e) => HttpResponse::from_engine_error(e),
+        },
+        "PUT" => match serde_json::from_slice::<SetBody>(&request.body) {
+            Ok(SetBody { value }) => match engine.set(key, value) {
+                Ok(()) => HttpResponse::no_content(),
+                Err(e) => HttpResponse::from_engine_error(e),
+            },
+            Err(e) => HttpResponse::error(400, &format!("Invalid body: {}", e)),
+        },
+        "DELETE" => match engine.remove(key) {
+            Ok(()) => HttpResponse::no_content(),
+            Err(e) => HttpResponse::from_engine_error(e),
+        },
+        _ => HttpResponse::method_not_allowed("GET, PUT, DELETE"),
+    }
+}
+
+/// `GET /keys?prefix=...`; without a prefix every pair is listed
+fn list<E: KvsEngine>(engine: &E, query: Option<&str>) -> HttpResponse {
+    let mut prefix = String::new();
+    for pair in query.unwrap_or_default().split('&') {
+        if let Some(value) = pair.strip_prefix("prefix=") {
+            match percent_decode(value, true) {
+                Some(value) => prefix = value,
+                None => return HttpResponse::error(400, "Invalid prefix"),
+            }
+        }
+    }
+
+    let mut entries = Vec::new();
+    for pair in engine.scan_prefix(&prefix) {
+        match pair {
+            Ok((key, value)) => entries.push(Entry { key, value }),
+            Err(e) => return HttpResponse::from_engine_error(e),
+        }
+    }
+    HttpResponse::json(200, &Entries { entries })
+}
+
+/// Read the request line, the headers and the body.
+/// Returns `None` if the client closed the connection without sending anything.
+fn read_request<R: BufRead, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<Option<HttpRequest>> {
+    let Some(request_line) = read_line(reader)? else {
+        return Ok(None);
+    };
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(malformed("invalid request line"));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(malformed("unsupported HTTP version"));
+    }
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_owned())),
+        None => (target, None),
+    };
+
+    let mut content_length = 0;
+    let mut expect_continue = false;
+    for _ in 0..=MAX_HEADERS {
+        let line = read_line(reader)?.ok_or_else(|| malformed("unexpected end of request"))?;
+        if line.is_empty() {
+            if expect_continue && content_length > 0 {
+                writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+                writer.flush()?;
+            }
+            // Grown while reading, so a client can't make us allocate a body it never sends
+            let mut body = Vec::new();
+            reader
+                .by_ref()
+                .take(content_length as u64)
+                .read_to_end(&mut body)
+                .map_err(|_| malformed("body shorter than Content-Length"))?;
+            if body.len() < content_length {
+                return Err(malformed("body shorter than Content-Length"));
+            }
+            return Ok(Some(HttpRequest {
+                method: method.to_owned(),
+                path: path.to_owned(),
+                query,
+                body,
+            }));
+        }
+
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| malformed("invalid header"))?;
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => {
+                content_length = value
+                    .parse()
+                    .ok()
+                    .filter(|&len| len <= MAX_BODY_LEN)
+                    .ok_or_else(|| malformed("invalid Content-Length"))?;
+            }
+            "transfer-encoding" => {
+                return Err(malformed("chunked bodies are not supported"));
+            }
+            "expect" => expect_continue = value.eq_ignore_ascii_case("100-continue"),
+            _ => {}
+        }
+    }
+    Err(malformed("too many headers"))
+}
+
+/// Read one CRLF (or LF) terminated line, without the terminator
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>> {
+    let mut line = Vec::new();
+    reader
+        .take(MAX_LINE_LEN as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(malformed("line too long or truncated"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| malformed("request is not valid UTF-8"))
+}
+
+fn malformed(message: &str) -> CustomError {
+    CustomError::Protocol(message.to_owned())
+}
+
+fn write_response<W: Write>(writer: &mut W, response: &HttpResponse) -> Result<()> {
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nConnection: close\r\n",
+        response.status,
+        reason(response.status)
+    )?;
+    if let Some(allow) = response.allow {
+        write!(writer, "Allow: {}\r\n", allow)?;
+    }
+    match &response.body {
+        Some(body) => {
+            write!(
+                writer,
+                "Content-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+                body.len()
+            )?;
+            writer.write_all(body.as_bytes())?;
+        }
+        None => writer.write_all(b"\r\n")?,
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Decode `%XX` escapes, and in query strings `+` as a space
+fn percent_decode(s: &str, in_query: bool) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut input = s.bytes();
+    while let Some(b) = input.next() {
+        match b {
+            b'%' => {
+                let hex = [input.next()?, input.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            b'+' if in_query => bytes.push(b' '),
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
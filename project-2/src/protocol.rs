@@ -0,0 +1,245 @@
+//! The binary protocol spoken between `kvs-client` and `kvs-server`.
+//! See `PROTOCOL.md` for the byte-level description.
+use crate::error::{CustomError, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+
+/// The bytes every handshake starts with
+pub const MAGIC: [u8; 4] = *b"KVSP";
+/// The protocol version this build speaks
+pub const PROTOCOL_VERSION: u16 = 1;
+/// The largest frame payload either side accepts, in bytes
+pub const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+const HANDSHAKE_ACCEPTED: u8 = 0;
+const HANDSHAKE_REJECTED: u8 = 1;
+
+/// A request sent from a client to the server
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Request {
+    /// Set a key to a value
+    Set {
+        /// The key to set
+        key: String,
+        /// The value to store under the key
+        value: String,
+    },
+    /// Get the value of a key
+    Get {
+        /// The key to look up
+        key: String,
+    },
+    /// Remove a key and its value
+    Remove {
+        /// The key to remove
+        key: String,
+    },
+}
+
+/// The server's reply to a single request
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+    /// The request succeeded, carrying the value for `Get` requests
+    Ok(Option<String>),
+    /// The request failed
+    Err(ServerError),
+}
+
+/// A request together with the id the client picked for it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RequestFrame {
+    /// Echoed back in the matching response
+    pub id: u64,
+    /// The request itself
+    pub request: Request,
+}
+
+/// A response together with the id of the request it answers
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseFrame {
+    /// The id of the request this answers
+    pub id: u64,
+    /// The response itself
+    pub response: Response,
+}
+
+/// An error reported by the server, in a form that can travel over the wire
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServerError {
+    /// What kind of error happened
+    pub code: ErrorCode,
+    /// A human readable description
+    pub message: String,
+}
+
+/// The numeric error codes of the protocol, derived from `CustomError`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "u16", into = "u16")]
+pub enum ErrorCode {
+    /// The key does not exist in the store
+    KeyNotFound,
+    /// The server hit an I/O error
+    Io,
+    /// The server failed to (de)serialize data
+    Serialization,
+    /// The request broke the protocol
+    Protocol,
+    /// Any other server side error
+    Internal,
+    /// A code this build does not know about
+    Unknown(u16),
+}
+
+impl From<u16> for ErrorCode {
+    fn from(code: u16) -> Self {
+        match code {
+            1 => ErrorCode::KeyNotFound,
+            2 => ErrorCode::Io,
+            3 => ErrorCode::Serialization,
+            4 => ErrorCode::Protocol,
+            5 => ErrorCode::Internal,
+            code => ErrorCode::Unknown(code),
+        }
+    }
+}
+
+impl From<ErrorCode> for u16 {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::KeyNotFound => 1,
+            ErrorCode::Io => 2,
+            ErrorCode::Serialization => 3,
+            ErrorCode::Protocol => 4,
+            ErrorCode::Internal => 5,
+            ErrorCode::Unknown(code) => code,
+        }
+    }
+}
+
+impl From<CustomError> for ServerError {
+    fn from(e: CustomError) -> Self {
+        let code = match e {
+            CustomError::KeyNotFound => ErrorCode::KeyNotFound,
+            CustomError::Io(_) => ErrorCode::Io,
+            CustomError::Serde(_) | CustomError::Bincode(_) => ErrorCode::Serialization,
+            CustomError::Protocol(_)
+            | CustomError::ProtocolVersion { .. }
+            | CustomError::UnexpectedResponse => ErrorCode::Protocol,
+            _ => ErrorCode::Internal,
+        };
+        ServerError {
+            code,
+            message: e.to_string(),
+        }
+    }
+}
+
+impl From<ServerError> for CustomError {
+    fn from(e: ServerError) -> Self {
+        match e.code {
+            ErrorCode::KeyNotFound => CustomError::KeyNotFound,
+            _ => CustomError::Server(e.message),
+        }
+    }
+}
+
+/// Write a length-prefixed frame holding the bincode encoding of `value`
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, value: &T) -> Result<()> {
+    let payload = bincode::serialize(value)?;
+    let len = u32::try_from(payload.len())
+        .ok()
+        .filter(|&len| len <= MAX_FRAME_LEN)
+        .ok_or_else(|| {
+            CustomError::Protocol(format!("frame of {} bytes is too large", payload.len()))
+        })?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Read one length-prefixed frame.
+/// Returns `None` if the peer closed the connection before a new frame started.
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_le_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(CustomError::Protocol(format!(
+            "frame of {} bytes is too large",
+            len
+        )));
+    }
+    // Grown while reading, so a peer can't make us allocate the maximum with four bytes
+    let mut payload = Vec::new();
+    reader.take(u64::from(len)).read_to_end(&mut payload)?;
+    if payload.len() < len as usize {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    let frame = bincode::deserialize(&payload)
+        .map_err(|e| CustomError::Protocol(format!("malformed frame: {}", e)))?;
+    Ok(Some(frame))
+}
+
+/// Client side of the handshake: announce our version and wait for the server to accept it
+pub fn client_handshake<S: Read + Write>(stream: &mut S) -> Result<()> {
+    write_hello(stream, PROTOCOL_VERSION, None)?;
+    let (version, status) = read_hello(stream, true)?;
+    match status {
+        Some(HANDSHAKE_ACCEPTED) if version == PROTOCOL_VERSION => Ok(()),
+        Some(HANDSHAKE_ACCEPTED) | Some(HANDSHAKE_REJECTED) => Err(CustomError::ProtocolVersion {
+            client: PROTOCOL_VERSION,
+            server: version,
+        }),
+        status => Err(CustomError::Protocol(format!(
+            "invalid handshake status {:?}",
+            status
+        ))),
+    }
+}
+
+/// Server side of the handshake: read the client's version, then accept or reject it.
+/// The connection must be closed after a rejection.
+pub fn server_handshake<S: Read + Write>(stream: &mut S) -> Result<()> {
+    let (version, _) = read_hello(stream, false)?;
+    if version == PROTOCOL_VERSION {
+        write_hello(stream, PROTOCOL_VERSION, Some(HANDSHAKE_ACCEPTED))
+    } else {
+        write_hello(stream, PROTOCOL_VERSION, Some(HANDSHAKE_REJECTED))?;
+        Err(CustomError::ProtocolVersion {
+            client: version,
+            server: PROTOCOL_VERSION,
+        })
+    }
+}
+
+fn write_hello<W: Write>(writer: &mut W, version: u16, status: Option<u8>) -> Result<()> {
+    let mut hello = MAGIC.to_vec();
+    hello.extend_from_slice(&version.to_le_bytes());
+    hello.extend(status);
+    writer.write_all(&hello)?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn read_hello<R: Read>(reader: &mut R, with_status: bool) -> Result<(u16, Option<u8>)> {
+    let mut hello = [0u8; 7];
+    let hello = if with_status {
+        &mut hello[..]
+    } else {
+        &mut hello[..6]
+    };
+    reader.read_exact(hello)?;
+    if hello[..4] != MAGIC {
+        return Err(CustomError::Protocol(
+            "peer does not speak the kvs protocol".to_owned(),
+        ));
+    }
+    let version = u16::from_le_bytes([hello[4], hello[5]]);
+    Ok((version, hello.get(6).copied()))
+}
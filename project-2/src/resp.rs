@@ -0,0 +1,521 @@
+//! A Redis compatible front end, so `redis-cli` and Redis client libraries can use the store.
+//! Clients start out speaking RESP2 and may switch to RESP3 with `HELLO 3`.
+//! Supported commands: GET, SET, DEL, EXISTS, SCAN, plus the connection
+//! housekeeping clients send on their own (PING, ECHO, HELLO, SELECT, CLIENT, COMMAND, QUIT).
+use crate::error::{CustomError, Result};
+use crate::server::Executor;
+use crate::KvsEngine;
+use log::{debug, info};
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::ops::Bound;
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// The longest bulk string a client may send, in bytes
+const MAX_BULK_LEN: usize = 64 * 1024 * 1024;
+/// The most arguments a single command may have
+const MAX_ARGS: usize = 1024 * 1024;
+/// The longest header or inline command line, in bytes
+const MAX_LINE_LEN: usize = 64 * 1024;
+/// How many keys SCAN looks at when the client gives no COUNT
+const DEFAULT_SCAN_COUNT: usize = 10;
+/// How many SCAN cursors are remembered; the oldest are forgotten first
+const MAX_SCAN_CURSORS: usize = 4096;
+
+/// The keys unfinished SCANs stopped at, by cursor.
+/// Redis clients parse cursors as numbers, so the key a scan resumes after is kept here
+/// instead of in the cursor. The server shares one set between its connections, since
+/// client libraries may send the pages of one scan over different pooled connections.
+#[derive(Default)]
+pub(crate) struct ScanCursors {
+    inner: Mutex<ScanCursorsInner>,
+}
+
+#[derive(Default)]
+struct ScanCursorsInner {
+    /// The last cursor handed out; `0` stands for the start and end of a scan
+    last: u64,
+    keys: BTreeMap<u64, String>,
+}
+
+impl ScanCursors {
+    /// A new cursor that resumes after `key`
+    fn save(&self, key: String) -> u64 {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner.last += 1;
+        let cursor = inner.last;
+        inner.keys.insert(cursor, key);
+        if inner.keys.len() > MAX_SCAN_CURSORS {
+            inner.keys.pop_first();
+        }
+        cursor
+    }
+
+    /// The key `cursor` resumes after. It stays valid, so a client can retry a page.
+    fn key(&self, cursor: u64) -> Option<String> {
+        let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner.keys.get(&cursor).cloned()
+    }
+}
+
+/// A reply to a RESP client.
+/// `Null` and `Map` are written differently depending on the protocol version.
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(String),
+    Null,
+    Array(Vec<Reply>),
+    Map(Vec<(Reply, Reply)>),
+}
+
+/// Serve one RESP connection until the client quits or hangs up
+pub(crate) fn serve<E: KvsEngine>(
+    engine: &E,
+    tcp: TcpStream,
+    executor: &Executor,
+    cursors: &Arc<ScanCursors>,
+) -> Result<()> {
+    let peer = tcp.peer_addr()?;
+    debug!(peer:% = peer; "RESP client connected");
+    let mut reader = BufReader::new(&tcp);
+    let mut writer = BufWriter::new(&tcp);
+    let mut proto = 2;
+
+    loop {
+        let mut args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            Err(CustomError::Protocol(message)) => {
+                // Like Redis, report the protocol error and hang up.
+                let reply = Reply::Error(format!("ERR Protocol error: {}", message));
+                write_reply(&mut writer, &reply, proto)?;
+                writer.flush()?;
+                return Err(CustomError::Protocol(message));
+            }
+            Err(e) => return Err(e),
+        };
+        if args.is_empty() {
+            continue;
+        }
+        let name = String::from_utf8_lossy(&args.remove(0)).to_ascii_uppercase();
+        info!(peer:% = peer, command = name.as_str(), args = args.len(); "RESP request");
+
+        let (engine, command, cursors) = (engine.clone(), name.clone(), Arc::clone(cursors));
+        let (replied, switched_to) = executor.run(move || {
+            let replied = execute(&engine, &command, &args, &mut proto, &cursors);
+            (replied, proto)
+        })?;
+        proto = switched_to;
+        let reply = match replied {
+            Ok(reply) => reply,
+            Err(message) => {
+                debug!(peer:% = peer, command = name.as_str(), error = message.as_str(); "RESP request failed");
+                Reply::Error(message)
+            }
+        };
+        write_reply(&mut writer, &reply, proto)?;
+        // Answer pipelined commands in one write.
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+        if name == "QUIT" {
+            break;
+        }
+    }
+
+    writer.flush()?;
+    debug!(peer:% = peer; "RESP client disconnected");
+    Ok(())
+}
+
+/// Run one command. Errors are returned as the text of the RESP error frame.
+fn execute<E: KvsEngine>(
+    engine: &E,
+    name: &str,
+    args: &[Vec<u8>],
+    proto: &mut u8,
+    cursors: &ScanCursors,
+) -> std::result::Result<Reply, String> {
+    let arity = |min: usize, max: usize| {
+        if args.len() < min || args.len() > max {
+            Err(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name.to_ascii_lowercase()
+            ))
+        } else {
+            Ok(())
+        }
+    };
+
+    match name {
+        "GET" => {
+            arity(1, 1)?;
+            match engine.get(utf8(&args[0])?).map_err(error_message)? {
+                Some(value) => Ok(Reply::Bulk(value)),
+                None => Ok(Reply::Null),
+            }
+        }
+        "SET" => {
+            arity(2, usize::MAX)?;
+            if args.len() > 2 {
+                return Err("ERR syntax error".to_owned());
+            }
+            engine
+                .set(utf8(&args[0])?, utf8(&args[1])?)
+                .map_err(error_message)?;
+            Ok(Reply::Simple("OK"))
+        }
+        "DEL" => {
+            arity(1, usize::MAX)?;
+            // Like Redis, DEL counts the keys it removed instead of failing on missing ones.
+            let mut removed = 0;
+            for key in args {
+                match engine.remove(utf8(key)?) {
+                    Ok(()) => removed += 1,
+                    Err(CustomError::KeyNotFound) => {}
+                    Err(e) => return Err(error_message(e)),
+                }
+            }
+            Ok(Reply::Integer(removed))
+        }
+        "EXISTS" => {
+            arity(1, usize::MAX)?;
+            let mut found = 0;
+            for key in args {
+                if engine.get(utf8(key)?).map_err(error_message)?.is_some() {
+                    found += 1;
+                }
+            }
+            Ok(Reply::Integer(found))
+        }
+        "SCAN" => {
+            arity(1, usize::MAX)?;
+            scan(engine, args, cursors)
+        }
+        "PING" => {
+            arity(0, 1)?;
+            match args.first() {
+                Some(message) => Ok(Reply::Bulk(utf8(message)?)),
+                None => Ok(Reply::Simple("PONG")),
+            }
+        }
+        "ECHO" => {
+            arity(1, 1)?;
+            Ok(Reply::Bulk(utf8(&args[0])?))
+        }
+        "HELLO" => {
+            if let Some(version) = args.first() {
+                *proto = match version.as_slice() {
+                    b"2" => 2,
+                    b"3" => 3,
+                    _ => return Err("NOPROTO unsupported protocol version".to_owned()),
+                };
+            }
+            Ok(Reply::Map(vec![
+                (bulk("server"), bulk("kvs")),
+                (bulk("version"), bulk(env!("CARGO_PKG_VERSION"))),
+                (bulk("proto"), Reply::Integer(i64::from(*proto))),
+                (bulk("id"), Reply::Integer(0)),
+                (bulk("mode"), bulk("standalone")),
+                (bulk("role"), bulk("master")),
+                (bulk("modules"), Reply::Array(Vec::new())),
+            ]))
+        }
+        "SELECT" => {
+            arity(1, 1)?;
+            match args[0].as_slice() {
+                b"0" => Ok(Reply::Simple("OK")),
+                _ => Err("ERR DB index is out of range".to_owned()),
+            }
+        }
+        // Client libraries name their connections on connect; there is nothing to record.
+        "CLIENT" => Ok(Reply::Simple("OK")),
+        // `redis-cli` asks for command docs on startup; it copes with an empty answer.
+        "COMMAND" => Ok(Reply::Array(Vec::new())),
+        "QUIT" => Ok(Reply::Simple("OK")),
+        _ => Err(format!(
+            "ERR unknown command '{}'",
+            name.to_ascii_lowercase()
+        )),
+    }
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`.
+/// `0` starts and ends a scan; any other cursor stands for the last key looked at, so keys
+/// removed between calls don't make the scan skip the ones after them.
+fn scan<E: KvsEngine>(
+    engine: &E,
+    args: &[Vec<u8>],
+    cursors: &ScanCursors,
+) -> std::result::Result<Reply, String> {
+    let invalid_cursor = || "ERR invalid cursor".to_owned();
+    let start = match utf8(&args[0])?.parse().map_err(|_| invalid_cursor())? {
+        0 => Bound::Unbounded,
+        cursor => Bound::Excluded(cursors.key(cursor).ok_or_else(invalid_cursor)?),
+    };
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    for option in args[1..].chunks(2) {
+        let [name, value] = option else {
+            return Err("ERR syntax error".to_owned());
+        };
+        match name.to_ascii_uppercase().as_slice() {
+            b"MATCH" => pattern = Some(value.as_slice()),
+            b"COUNT" => {
+                count = utf8(value)?
+                    .parse()
+                    .ok()
+                    .filter(|&count| count > 0)
+                    .ok_or_else(|| "ERR value is not an integer or out of range".to_owned())?;
+            }
+            _ => return Err("ERR syntax error".to_owned()),
+        }
+    }
+
+    // One key past the batch tells whether the scan is done.
+    let mut keys = Vec::with_capacity(count);
+    let mut done = true;
+    for pair in engine.scan((start, Bound::Unbounded)) {
+        let (key, _) = pair.map_err(error_message)?;
+        if keys.len() == count {
+            done = false;
+            break;
+        }
+        keys.push(key);
+    }
+    let next = match keys.last() {
+        Some(last) if !done => cursors.save(last.clone()),
+        _ => 0,
+    };
+    let batch = keys
+        .into_iter()
+        .filter(|key| pattern.is_none_or(|pattern| glob_match(pattern, key.as_bytes())))
+        .map(Reply::Bulk)
+        .collect();
+    Ok(Reply::Array(vec![
+        Reply::Bulk(next.to_string()),
+        Reply::Array(batch),
+    ]))
+}
+
+fn bulk(s: &str) -> Reply {
+    Reply::Bulk(s.to_owned())
+}
+
+fn utf8(arg: &[u8]) -> std::result::Result<String, String> {
+    String::from_utf8(arg.to_vec())
+        .map_err(|_| "ERR keys and values must be valid UTF-8".to_owned())
+}
+
+/// The text of the error frame reporting an engine error
+fn error_message(e: CustomError) -> String {
+    match e {
+        CustomError::KeyNotFound => "ERR no such key".to_owned(),
+        e => format!("ERR {}", e),
+    }
+}
+
+/// Read the next command, either as a RESP array of bulk strings or as an inline command.
+/// Returns `None` once the client closed the connection.
+fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(reader)? else {
+        return Ok(None);
+    };
+    let Some(count) = line.strip_prefix(b"*") else {
+        // Inline commands are what you get when typing into telnet.
+        return Ok(Some(
+            line.split(|b| b.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect(),
+        ));
+    };
+
+    let count = parse_len(count, MAX_ARGS, "multibulk length")?;
+    let mut args = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or_else(unexpected_eof)?;
+        let len = match line.strip_prefix(b"$") {
+            Some(len) => parse_len(len, MAX_BULK_LEN, "bulk length")?,
+            None => {
+                return Err(CustomError::Protocol(format!(
+                    "expected '$', got '{}'",
+                    String::from_utf8_lossy(&line[..line.len().min(1)])
+                )))
+            }
+        };
+        // Grown while reading, so a client can't make us allocate a bulk it never sends
+        let mut arg = Vec::new();
+        reader.by_ref().take(len as u64 + 2).read_to_end(&mut arg)?;
+        if arg.len() < len + 2 {
+            return Err(unexpected_eof());
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err(CustomError::Protocol(
+                "bulk string not terminated".to_owned(),
+            ));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Read one CRLF (or LF) terminated line, without the terminator
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    reader
+        .take(MAX_LINE_LEN as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(match line.len() > MAX_LINE_LEN {
+            true => CustomError::Protocol("too big inline request".to_owned()),
+            false => unexpected_eof(),
+        });
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8], max: usize, what: &str) -> Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse::<usize>().ok())
+        .filter(|&len| len <= max)
+        .ok_or_else(|| CustomError::Protocol(format!("invalid {}", what)))
+}
+
+fn unexpected_eof() -> CustomError {
+    CustomError::Protocol("unexpected end of stream".to_owned())
+}
+
+fn write_reply<W: Write>(writer: &mut W, reply: &Reply, proto: u8) -> Result<()> {
+    match reply {
+        Reply::Simple(s) => write!(writer, "+{}\r\n", s)?,
+        Reply::Error(e) => write!(writer, "-{}\r\n", e.replace(['\r', '\n'], " "))?,
+        Reply::Integer(n) => write!(writer, ":{}\r\n", n)?,
+        Reply::Bulk(s) => {
+            write!(writer, "${}\r\n", s.len())?;
+            writer.write_all(s.as_bytes())?;
+            writer.write_all(b"\r\n")?;
+        }
+        Reply::Null if proto >= 3 => writer.write_all(b"_\r\n")?,
+        Reply::Null => writer.write_all(b"$-1\r\n")?,
+        Reply::Array(items) => {
+            write!(writer, "*{}\r\n", items.len())?;
+            for item in items {
+                write_reply(writer, item, proto)?;
+            }
+        }
+        Reply::Map(pairs) => {
+            // RESP2 has no maps; they are sent as a flat array of keys and values.
+            if proto >= 3 {
+                write!(writer, "%{}\r\n", pairs.len())?;
+            } else {
+                write!(writer, "*{}\r\n", pairs.len() * 2)?;
+            }
+            for (key, value) in pairs {
+                write_reply(writer, key, proto)?;
+                write_reply(writer, value, proto)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Redis style glob matching: `*`, `?`, `[abc]`, `[^a-z]` and `\` escapes.
+/// Runs in `O(pattern * text)` steps, however many stars the pattern has.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // The pattern after the last star, and where in the text the star stopped matching.
+    // Earlier stars never need retrying: the last one can take whatever they would.
+    let mut star = None;
+    loop {
+        if p == pattern.len() && t == text.len() {
+            return true;
+        }
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, t));
+            continue;
+        }
+        if let Some(len) = match_one(&pattern[p..], text.get(t).copied()) {
+            p += len;
+            t += 1;
+            continue;
+        }
+        // Let the last star take one more character and try again
+        match star {
+            Some((after_star, star_end)) if star_end < text.len() => {
+                p = after_star;
+                t = star_end + 1;
+                star = Some((after_star, t));
+            }
+            _ => return false,
+        }
+    }
+}
+
+/// Match the start of `pattern` against the character `c`, returning how many bytes of the
+/// pattern it took. There is no match at the end of either.
+fn match_one(pattern: &[u8], c: Option<u8>) -> Option<usize> {
+    let c = c?;
+    match pattern {
+        [] => None,
+        [b'?', ..] => Some(1),
+        [b'[', rest @ ..] => match match_class(rest) {
+            Some((class, after)) => class(c).then_some(pattern.len() - after.len()),
+            // An unterminated class is matched literally.
+            None => (c == b'[').then_some(1),
+        },
+        [b'\\', escaped, ..] => (c == *escaped).then_some(2),
+        [literal, ..] => (c == *literal).then_some(1),
+    }
+}
+
+/// Parse a `[...]` class (after the opening bracket) into a predicate and the rest of the pattern
+fn match_class(pattern: &[u8]) -> Option<(impl Fn(u8) -> bool + '_, &[u8])> {
+    let (negate, body) = match pattern.split_first() {
+        Some((b'^', body)) => (true, body),
+        _ => (false, pattern),
+    };
+    let mut i = 0;
+    while i < body.len() && body[i] != b']' {
+        i += if body[i] == b'\\' { 2 } else { 1 };
+    }
+    if i >= body.len() {
+        return None;
+    }
+    let (class, rest) = (&body[..i], &body[i + 1..]);
+    let matches = move |c: u8| {
+        let mut found = false;
+        let mut j = 0;
+        while j < class.len() {
+            match class[j..] {
+                [b'\\', escaped, ..] => {
+                    found |= c == escaped;
+                    j += 2;
+                }
+                [lo, b'-', hi, ..] => {
+                    found |= lo.min(hi) <= c && c <= lo.max(hi);
+                    j += 3;
+                }
+                [single, ..] => {
+                    found |= c == single;
+                    j += 1;
+                }
+                [] => break,
+            }
+        }
+        found != negate
+    };
+    Some((matches, rest))
+}
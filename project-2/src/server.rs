@@ -0,0 +1,354 @@
+use crate::protocol::{
+    self, ErrorCode, Request, RequestFrame, Response, ResponseFrame, ServerError,
+};
+use crate::thread_pool::ThreadPool;
+use crate::{http, resp, CompactionReport, CustomError, KvsEngine, Result};
+use log::{debug, error, info};
+use std::io::{self, BufReader, BufWriter};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The protocol a server speaks to its clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frontend {
+    /// The native binary protocol described in `PROTOCOL.md`
+    Kvs,
+    /// The Redis protocol (RESP2, or RESP3 after `HELLO 3`)
+    Resp,
+    /// A JSON REST API over HTTP/1.1
+    Http,
+}
+
+/// The server of the Key Value Store.
+/// It owns one engine for its whole lifetime. Every connection is read on a thread of its own,
+/// and each request it sends runs as a job on the thread pool, so clients that keep an idle
+/// connection open never hold a thread of the pool.
+/// Every connection gets its own clone of the engine, so requests from
+/// different clients run in parallel.
+/// It can listen on more than one address, with a protocol for each.
+pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
+    engine: E,
+    pool: P,
+    frontend: Frontend,
+    compact_when_idle: Option<Duration>,
+    /// The addresses to listen on besides the one passed to `run`
+    listeners: Vec<(Frontend, SocketAddr)>,
+}
+
+impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
+    /// Create a server around an already opened engine, speaking the given protocol
+    pub fn new(engine: E, pool: P, frontend: Frontend) -> Self {
+        KvsServer {
+            engine,
+            pool,
+            frontend,
+            compact_when_idle: None,
+            listeners: Vec::new(),
+        }
+    }
+
+    /// Also listen on `addr` and speak `frontend` there.
+    /// Connections to every address share the engine and the thread pool.
+    pub fn listen(mut self, frontend: Frontend, addr: SocketAddr) -> Self {
+        self.listeners.push((frontend, addr));
+        self
+    }
+
+    /// Compact the engine once no request has come in for `idle`.
+    /// The server only compacts again after new requests, so an idle server stays quiet.
+    pub fn compact_when_idle(mut self, idle: Duration) -> Self {
+        self.compact_when_idle = Some(idle);
+        self
+    }
+
+    /// Listen on the given address, and those added with `listen`, and serve clients until
+    /// the process is stopped
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        // Every address is bound before any client is served, so a taken one fails the start.
+        let mut listeners = vec![(self.frontend, TcpListener::bind(addr)?)];
+        for &(frontend, addr) in &self.listeners {
+            listeners.push((frontend, TcpListener::bind(addr)?));
+        }
+
+        // Each listener accepts on its own thread and hands the connections over to this one,
+        // which starts their threads and runs their requests on the pool.
+        let (sender, events) = mpsc::channel();
+        for (frontend, listener) in listeners {
+            info!(
+                addr:% = listener.local_addr()?,
+                frontend:? = frontend,
+                protocol_version = protocol::PROTOCOL_VERSION;
+                "Listening"
+            );
+            let sender = sender.clone();
+            thread::spawn(move || accept(&listener, frontend, &sender));
+        }
+        let executor = Executor { events: sender };
+        let scan_cursors = Arc::new(resp::ScanCursors::default());
+
+        let activity = Arc::new(Activity::default());
+        if let Some(idle) = self.compact_when_idle {
+            let engine = self.engine.clone();
+            let activity = Arc::clone(&activity);
+            thread::spawn(move || compact_when_idle(&engine, &activity, idle));
+        }
+        for event in events {
+            let (frontend, stream) = match event {
+                Event::Job(job) => {
+                    self.pool.spawn(job);
+                    continue;
+                }
+                Event::Connection(frontend, stream) => (frontend, stream),
+            };
+            let engine = Tracked {
+                engine: self.engine.clone(),
+                activity: Arc::clone(&activity),
+            };
+            let executor = executor.clone();
+            let scan_cursors = Arc::clone(&scan_cursors);
+            let started = thread::Builder::new().spawn(move || {
+                let served = match frontend {
+                    Frontend::Kvs => serve(&engine, stream, &executor),
+                    Frontend::Resp => resp::serve(&engine, stream, &executor, &scan_cursors),
+                    Frontend::Http => http::serve(&engine, stream, &executor),
+                };
+                if let Err(e) = served {
+                    error!(error:% = e; "Error serving client");
+                }
+            });
+            if let Err(e) = started {
+                error!(error:% = e; "Failed to start a connection thread");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What the listener and connection threads hand over to the thread that owns the pool
+enum Event {
+    /// A connection a listener accepted
+    Connection(Frontend, TcpStream),
+    /// A request to run on the pool
+    Job(Box<dyn FnOnce() + Send>),
+}
+
+/// Runs the requests of a connection on the thread pool of the server
+#[derive(Clone)]
+pub(crate) struct Executor {
+    events: mpsc::Sender<Event>,
+}
+
+impl Executor {
+    /// Run `job` on the pool and wait for its result
+    pub(crate) fn run<T, F>(&self, job: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (sender, result) = mpsc::sync_channel(1);
+        let job = Box::new(move || {
+            let _ = sender.send(job());
+        });
+        if self.events.send(Event::Job(job)).is_err() {
+            return Err(io::Error::other("the server stopped").into());
+        }
+        // A job that panicked drops the sender without sending anything
+        result
+            .recv()
+            .map_err(|_| io::Error::other("the request panicked").into())
+    }
+}
+
+/// Accept connections on `listener` and send them on, until the server is gone
+fn accept(listener: &TcpListener, frontend: Frontend, events: &mpsc::Sender<Event>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if events.send(Event::Connection(frontend, stream)).is_err() {
+                    return;
+                }
+            }
+            Err(e) => error!(error:% = e; "Connection failed"),
+        }
+    }
+}
+
+/// When the server last got a request
+struct Activity {
+    started: Instant,
+    /// Milliseconds from `started` to the last request, plus one.
+    /// Zero once the engine has been compacted since.
+    last_request: AtomicU64,
+}
+
+impl Default for Activity {
+    fn default() -> Activity {
+        Activity {
+            started: Instant::now(),
+            last_request: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Activity {
+    fn record(&self) {
+        let now = self.started.elapsed().as_millis() as u64 + 1;
+        self.last_request.store(now, Ordering::Relaxed);
+    }
+
+    /// Whether requests came in since the last compaction, and the last one at least
+    /// `idle` ago. Returning true counts as compacting.
+    fn take_idle(&self, idle: Duration) -> bool {
+        let last_request = self.last_request.load(Ordering::Relaxed);
+        let idle_since = Duration::from_millis(last_request.saturating_sub(1));
+        last_request != 0
+            && self.started.elapsed() >= idle_since + idle
+            && self
+                .last_request
+                .compare_exchange(last_request, 0, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+    }
+}
+
+/// Compact the engine whenever the server has been idle for `idle`
+fn compact_when_idle<E: KvsEngine>(engine: &E, activity: &Activity, idle: Duration) {
+    let poll = idle.clamp(Duration::from_millis(10), Duration::from_secs(1));
+    loop {
+        thread::sleep(poll);
+        if !activity.take_idle(idle) {
+            continue;
+        }
+        match engine.compact() {
+            Ok(report) => info!(
+                files = report.files_rewritten,
+                bytes_reclaimed = report.bytes_reclaimed,
+                duration:? = report.duration;
+                "Compacted while idle"
+            ),
+            Err(e) => error!(error:% = e; "Idle compaction failed"),
+        }
+    }
+}
+
+/// An engine handle that records every request in the server's `Activity`
+#[derive(Clone)]
+struct Tracked<E> {
+    engine: E,
+    activity: Arc<Activity>,
+}
+
+impl<E: KvsEngine> KvsEngine for Tracked<E> {
+    type Scan = E::Scan;
+
+    fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Tracked {
+            engine: E::open(path)?,
+            activity: Arc::default(),
+        })
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.activity.record();
+        self.engine.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.activity.record();
+        self.engine.get(key)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        self.activity.record();
+        self.engine.keys()
+    }
+
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> E::Scan {
+        self.activity.record();
+        self.engine.scan(range)
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> E::Scan {
+        self.activity.record();
+        self.engine.scan_prefix(prefix)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.activity.record();
+        self.engine.remove(key)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+
+    fn compact(&self) -> Result<CompactionReport> {
+        self.engine.compact()
+    }
+}
+
+/// Answer every request the client sends until it closes the connection
+fn serve<E: KvsEngine>(engine: &E, mut tcp: TcpStream, executor: &Executor) -> Result<()> {
+    let peer = tcp.peer_addr()?;
+    debug!(peer:% = peer; "Client connected");
+    protocol::server_handshake(&mut tcp)?;
+    let mut reader = BufReader::new(&tcp);
+    let mut writer = BufWriter::new(&tcp);
+
+    loop {
+        let RequestFrame { id, request } = match protocol::read_frame(&mut reader) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            // Tell the client what it got wrong before hanging up. Its request id is unknown.
+            Err(e @ CustomError::Protocol(_)) => {
+                let response = Response::Err(ServerError {
+                    code: ErrorCode::Protocol,
+                    message: e.to_string(),
+                });
+                protocol::write_frame(&mut writer, &ResponseFrame { id: 0, response })?;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        let engine = engine.clone();
+        let response = executor.run(move || execute(&engine, peer, id, request))?;
+        if let Response::Err(e) = &response {
+            debug!(peer:% = peer, id = id, code:? = e.code, error = e.message.as_str(); "Request failed");
+        }
+        protocol::write_frame(&mut writer, &ResponseFrame { id, response })?;
+    }
+
+    debug!(peer:% = peer; "Client disconnected");
+    Ok(())
+}
+
+/// Run one request against the engine
+fn execute<E: KvsEngine>(engine: &E, peer: SocketAddr, id: u64, request: Request) -> Response {
+    match request {
+        Request::Set { key, value } => {
+            info!(peer:% = peer, id = id, command = "set", key:% = key; "Request");
+            match engine.set(key, value) {
+                Ok(()) => Response::Ok(None),
+                Err(e) => Response::Err(e.into()),
+            }
+        }
+        Request::Get { key } => {
+            info!(peer:% = peer, id = id, command = "get", key:% = key; "Request");
+            match engine.get(key) {
+                Ok(value) => Response::Ok(value),
+                Err(e) => Response::Err(e.into()),
+            }
+        }
+        Request::Remove { key } => {
+            info!(peer:% = peer, id = id, command = "rm", key:% = key; "Request");
+            match engine.remove(key) {
+                Ok(()) => Response::Ok(None),
+                Err(e) => Response::Err(e.into()),
+            }
+        }
+    }
+}
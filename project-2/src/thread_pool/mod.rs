@@ -0,0 +1,23 @@
+use crate::Result;
+
+mod naive;
+mod rayon;
+mod shared_queue;
+
+pub use self::naive::NaiveThreadPool;
+pub use self::rayon::RayonThreadPool;
+pub use self::shared_queue::SharedQueueThreadPool;
+
+/// A pool of threads that runs jobs concurrently.
+/// A panicking job must not take the pool down with it.
+pub trait ThreadPool {
+    /// Create a pool with the given number of threads
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Run a job on one of the pool's threads
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
@@ -0,0 +1,20 @@
+use super::ThreadPool;
+use crate::Result;
+use std::thread;
+
+/// A "pool" that starts a new thread for every job.
+/// The thread count is ignored; it is the baseline the other pools are compared against.
+pub struct NaiveThreadPool;
+
+impl ThreadPool for NaiveThreadPool {
+    fn new(_threads: u32) -> Result<Self> {
+        Ok(NaiveThreadPool)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        thread::spawn(job);
+    }
+}
@@ -0,0 +1,26 @@
+use super::ThreadPool;
+use crate::error::{CustomError, Result};
+use log::error;
+
+/// A work-stealing pool backed by `rayon`: every thread has its own queue
+/// and idle threads steal jobs from busy ones.
+pub struct RayonThreadPool(rayon::ThreadPool);
+
+impl ThreadPool for RayonThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            // Without a handler rayon aborts the process when a job panics.
+            .panic_handler(|_| error!("A job panicked in the rayon thread pool"))
+            .build()
+            .map_err(|e| CustomError::BoxedError(Box::new(e)))?;
+        Ok(RayonThreadPool(pool))
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.0.spawn(job);
+    }
+}
@@ -0,0 +1,56 @@
+use super::ThreadPool;
+use crate::Result;
+use crossbeam_channel::{Receiver, Sender};
+use log::error;
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed number of threads taking jobs from one shared queue.
+/// A worker whose job panics is replaced by a fresh one, so the pool keeps its size.
+pub struct SharedQueueThreadPool {
+    sender: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let (sender, receiver) = crossbeam_channel::unbounded::<Job>();
+        for _ in 0..threads {
+            spawn_worker(Worker(receiver.clone()))?;
+        }
+        Ok(SharedQueueThreadPool { sender })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender
+            .send(Box::new(job))
+            .expect("the pool's workers outlive its sender");
+    }
+}
+
+/// The receiving end of one worker thread.
+/// Dropping it while unwinding from a panicking job starts its replacement.
+struct Worker(Receiver<Job>);
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            if let Err(e) = spawn_worker(Worker(self.0.clone())) {
+                error!(error:% = e; "Failed to replace a panicked worker");
+            }
+        }
+    }
+}
+
+fn spawn_worker(worker: Worker) -> Result<()> {
+    thread::Builder::new().spawn(move || {
+        // Runs until the pool is dropped and the queue is closed.
+        for job in worker.0.iter() {
+            job();
+        }
+    })?;
+    Ok(())
+}
@@ -0,0 +1,411 @@
+use assert_cmd::prelude::*;
+use common::ServerProcess;
+use kvs::protocol::{
+    self, ErrorCode, Request, RequestFrame, Response, ResponseFrame, PROTOCOL_VERSION,
+};
+use kvs::{CustomError, KvsClient, Result};
+use predicates::ord::eq;
+use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+
+// Shakes hands, sends every request over one connection and collects the responses.
+fn send(mut stream: &TcpStream, requests: Vec<Request>) -> Vec<Response> {
+    protocol::client_handshake(&mut stream).unwrap();
+    requests
+        .into_iter()
+        .enumerate()
+        .map(|(id, request)| {
+            let id = id as u64;
+            protocol::write_frame(&mut stream, &RequestFrame { id, request }).unwrap();
+            let frame: ResponseFrame = protocol::read_frame(&mut stream).unwrap().unwrap();
+            assert_eq!(frame.id, id);
+            frame.response
+        })
+        .collect()
+}
+
+// `kvs-server -V` should print the version
+#[test]
+fn server_cli_version() {
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["-V"])
+        .assert()
+        .stdout(contains(env!("CARGO_PKG_VERSION")));
+}
+
+#[test]
+fn server_cli_invalid_args() {
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "not-an-address"])
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "unknown"])
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["extra"])
+        .assert()
+        .failure();
+}
+
+fn serve_requests(engine: &str) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = ServerProcess::start(&temp_dir, engine);
+
+    let stream = server.connect();
+    let responses = send(
+        &stream,
+        vec![
+            Request::Set {
+                key: "key1".to_owned(),
+                value: "value1".to_owned(),
+            },
+            Request::Get {
+                key: "key1".to_owned(),
+            },
+            Request::Remove {
+                key: "key2".to_owned(),
+            },
+        ],
+    );
+    assert!(matches!(responses[0], Response::Ok(None)));
+    assert!(matches!(&responses[1], Response::Ok(Some(value)) if value == "value1"));
+    assert!(matches!(&responses[2], Response::Err(e) if e.code == ErrorCode::KeyNotFound));
+    drop(stream);
+
+    // A second client sees what the first one wrote.
+    let stream = server.connect();
+    let responses = send(
+        &stream,
+        vec![
+            Request::Remove {
+                key: "key1".to_owned(),
+            },
+            Request::Get {
+                key: "key1".to_owned(),
+            },
+        ],
+    );
+    assert!(matches!(responses[0], Response::Ok(None)));
+    assert!(matches!(responses[1], Response::Ok(None)));
+}
+
+#[test]
+fn server_kvs_engine() {
+    serve_requests("kvs");
+}
+
+#[test]
+fn server_memory_engine() {
+    serve_requests("memory");
+}
+
+// Clients that keep idle connections open don't hold the threads of the pool.
+#[test]
+fn server_more_idle_clients_than_threads() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = ServerProcess::start_with_args(&temp_dir, &["--threads", "2"]);
+    let idle: Vec<_> = (0..3).map(|_| server.connect()).collect();
+
+    let stream = server.connect();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let responses = send(
+        &stream,
+        vec![Request::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        }],
+    );
+    assert!(matches!(responses[0], Response::Ok(None)));
+    drop(idle);
+}
+
+// Extra listeners speak their own protocol to the same engine.
+#[test]
+fn server_extra_listeners() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (resp_addr, http_addr) = (common::free_addr(), common::free_addr());
+    let server = ServerProcess::start_with_args(
+        &temp_dir,
+        &[
+            "--resp-addr",
+            &resp_addr.to_string(),
+            "--http-addr",
+            &http_addr.to_string(),
+        ],
+    );
+
+    let stream = server.connect();
+    let responses = send(
+        &stream,
+        vec![Request::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        }],
+    );
+    assert!(matches!(responses[0], Response::Ok(None)));
+    // Every connection holds a thread of the pool until it closes
+    drop(stream);
+
+    let mut redis = server.connect_to(resp_addr);
+    redis.write_all(b"SET key2 value2\r\nGET key1\r\n").unwrap();
+    let mut replies = [0u8; 17];
+    redis.read_exact(&mut replies).unwrap();
+    assert_eq!(&replies, b"+OK\r\n$6\r\nvalue1\r\n");
+    drop(redis);
+
+    let mut http = server.connect_to(http_addr);
+    http.write_all(b"GET /keys/key2 HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    http.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.contains(r#""value":"value2""#), "{}", response);
+}
+
+// Overwritten values are reclaimed once the server has been idle for a while.
+#[test]
+fn server_compacts_when_idle() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server =
+        ServerProcess::start_with_args(&temp_dir, &["--engine", "kvs", "--compact-when-idle", "1"]);
+    let log_size = || -> u64 {
+        std::fs::read_dir(&temp_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "bin"))
+            .map(|path| std::fs::metadata(path).unwrap().len())
+            .sum()
+    };
+
+    let stream = server.connect();
+    let requests = (0..10)
+        .map(|i| Request::Set {
+            key: "key1".to_owned(),
+            value: format!("{}-{}", i, "x".repeat(16 * 1024)),
+        })
+        .collect();
+    send(&stream, requests);
+    drop(stream);
+    assert!(log_size() > 100 * 1024);
+
+    for _ in 0..50 {
+        if log_size() < 32 * 1024 {
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("{} bytes of log files after going idle", log_size());
+}
+
+// `kvs-client -V` should print the version
+#[test]
+fn client_cli_version() {
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["-V"])
+        .assert()
+        .stdout(contains(env!("CARGO_PKG_VERSION")));
+}
+
+#[test]
+fn client_cli_invalid_args() {
+    Command::cargo_bin("kvs-client").unwrap().assert().failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "extra", "field"])
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "missing_field"])
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", "not-an-address"])
+        .assert()
+        .failure();
+}
+
+// The client subcommands should behave like the local `kvs` ones.
+#[test]
+fn client_cli_access_server() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = ServerProcess::start(&temp_dir, "kvs");
+    drop(server.connect());
+    let addr = server.addr.to_string();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", &addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &addr])
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", &addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &addr])
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", &addr])
+        .assert()
+        .failure()
+        .stdout(eq("Key not found").trim());
+}
+
+#[test]
+fn client_key_not_found_round_trips() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = ServerProcess::start(&temp_dir, "kvs");
+    drop(server.connect());
+
+    let mut client = KvsClient::connect(server.addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(CustomError::KeyNotFound)
+    ));
+    Ok(())
+}
+
+// The frames on the wire should match the layout described in PROTOCOL.md.
+#[test]
+fn protocol_byte_layout() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = ServerProcess::start(&temp_dir, "kvs");
+    let mut stream = server.connect();
+
+    stream.write_all(b"KVSP\x01\x00").unwrap();
+    let mut hello = [0u8; 7];
+    stream.read_exact(&mut hello).unwrap();
+    assert_eq!(&hello, b"KVSP\x01\x00\x00");
+
+    // Request id 7, `Remove` (variant 2) of the missing key "k".
+    let mut payload = 7u64.to_le_bytes().to_vec();
+    payload.extend(2u32.to_le_bytes());
+    payload.extend(1u64.to_le_bytes());
+    payload.extend(b"k");
+    stream
+        .write_all(&(payload.len() as u32).to_le_bytes())
+        .unwrap();
+    stream.write_all(&payload).unwrap();
+
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).unwrap();
+    let mut payload = vec![0u8; u32::from_le_bytes(len) as usize];
+    stream.read_exact(&mut payload).unwrap();
+    // Request id 7, `Err` (variant 1) with error code 1 (key not found).
+    assert_eq!(&payload[..8], &7u64.to_le_bytes());
+    assert_eq!(&payload[8..12], &1u32.to_le_bytes());
+    assert_eq!(&payload[12..14], &1u16.to_le_bytes());
+}
+
+// A frame the server can't read gets a protocol error before the server hangs up.
+#[test]
+fn server_reports_protocol_violation() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = ServerProcess::start(&temp_dir, "kvs");
+
+    let too_large = u32::MAX.to_le_bytes().to_vec();
+    let mut malformed = 4u32.to_le_bytes().to_vec();
+    malformed.extend(b"junk");
+    for frame in [too_large, malformed] {
+        let mut stream = server.connect();
+        protocol::client_handshake(&mut stream).unwrap();
+        stream.write_all(&frame).unwrap();
+
+        let frame: ResponseFrame = protocol::read_frame(&mut stream).unwrap().unwrap();
+        assert_eq!(frame.id, 0);
+        match frame.response {
+            Response::Err(e) => assert_eq!(e.code, ErrorCode::Protocol),
+            response => panic!("expected a protocol error, got {:?}", response),
+        }
+        assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+}
+
+// A server should reject a client speaking another protocol version and hang up.
+#[test]
+fn server_rejects_incompatible_version() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = ServerProcess::start(&temp_dir, "kvs");
+    let mut stream = server.connect();
+
+    stream.write_all(b"KVSP").unwrap();
+    stream
+        .write_all(&(PROTOCOL_VERSION + 1).to_le_bytes())
+        .unwrap();
+    let mut hello = [0u8; 7];
+    stream.read_exact(&mut hello).unwrap();
+    assert_eq!(&hello[..4], b"KVSP");
+    assert_eq!(u16::from_le_bytes([hello[4], hello[5]]), PROTOCOL_VERSION);
+    assert_eq!(hello[6], 1);
+    assert_eq!(stream.read(&mut hello).unwrap(), 0);
+}
+
+// A client should refuse to talk to a server speaking another protocol version.
+#[test]
+fn client_rejects_incompatible_version() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let fake_server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut hello = [0u8; 6];
+        stream.read_exact(&mut hello).unwrap();
+        stream.write_all(b"KVSP").unwrap();
+        stream
+            .write_all(&(PROTOCOL_VERSION + 1).to_le_bytes())
+            .unwrap();
+        stream.write_all(&[1]).unwrap();
+    });
+
+    match KvsClient::connect(addr) {
+        Err(CustomError::ProtocolVersion { client, server }) => {
+            assert_eq!(client, PROTOCOL_VERSION);
+            assert_eq!(server, PROTOCOL_VERSION + 1);
+        }
+        other => panic!("expected a protocol version error, got {:?}", other.err()),
+    }
+    fake_server.join().unwrap();
+}
@@ -0,0 +1,64 @@
+// Each test crate only uses some of these helpers.
+#![allow(dead_code)]
+
+use assert_cmd::prelude::*;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Starts `kvs-server` in `dir` on a free local port and kills it when dropped.
+pub struct ServerProcess {
+    child: Child,
+    pub addr: SocketAddr,
+}
+
+impl ServerProcess {
+    pub fn start(dir: &TempDir, engine: &str) -> ServerProcess {
+        ServerProcess::start_with_args(dir, &["--engine", engine])
+    }
+
+    pub fn start_with_args(dir: &TempDir, args: &[&str]) -> ServerProcess {
+        let addr = free_addr();
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(args)
+            .args(["--addr", &addr.to_string()])
+            .current_dir(dir)
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        ServerProcess { child, addr }
+    }
+
+    pub fn connect(&self) -> TcpStream {
+        self.connect_to(self.addr)
+    }
+
+    // Connects to another address the server listens on.
+    pub fn connect_to(&self, addr: SocketAddr) -> TcpStream {
+        for _ in 0..50 {
+            if let Ok(stream) = TcpStream::connect(addr) {
+                return stream;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("kvs-server did not start listening on {}", addr);
+    }
+}
+
+// A local address no one listens on right now.
+pub fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
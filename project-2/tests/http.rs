@@ -0,0 +1,115 @@
+use common::ServerProcess;
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use tempfile::TempDir;
+
+mod common;
+
+// Sends one request and returns the status code and the parsed JSON body, if any.
+fn request(server: &ServerProcess, method: &str, target: &str, body: Option<&str>) -> (u16, Value) {
+    let mut stream = server.connect();
+    let body = body.unwrap_or_default();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        target,
+        body.len(),
+        body
+    )
+    .unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    let body = if body.is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_str(body).unwrap()
+    };
+    (status, body)
+}
+
+fn start(temp_dir: &TempDir) -> ServerProcess {
+    ServerProcess::start_with_args(temp_dir, &["--protocol", "http"])
+}
+
+#[test]
+fn http_put_get_delete() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start(&temp_dir);
+
+    assert_eq!(
+        request(&server, "PUT", "/keys/key1", Some(r#"{"value":"value1"}"#)),
+        (204, Value::Null)
+    );
+    assert_eq!(
+        request(&server, "GET", "/keys/key1", None),
+        (200, json!({"key": "key1", "value": "value1"}))
+    );
+    assert_eq!(
+        request(&server, "DELETE", "/keys/key1", None),
+        (204, Value::Null)
+    );
+    assert_eq!(
+        request(&server, "GET", "/keys/key1", None),
+        (404, json!({"error": "Key not found"}))
+    );
+    assert_eq!(
+        request(&server, "DELETE", "/keys/key1", None),
+        (404, json!({"error": "Key not found"}))
+    );
+}
+
+#[test]
+fn http_list_by_prefix() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start(&temp_dir);
+
+    for (key, value) in [("user/1", "ann"), ("user/2", "bob"), ("team/1", "core")] {
+        let target = format!("/keys/{}", key.replace('/', "%2F"));
+        let body = json!({ "value": value }).to_string();
+        assert_eq!(request(&server, "PUT", &target, Some(&body)).0, 204);
+    }
+
+    assert_eq!(
+        request(&server, "GET", "/keys?prefix=user%2F", None),
+        (
+            200,
+            json!({"entries": [
+                {"key": "user/1", "value": "ann"},
+                {"key": "user/2", "value": "bob"},
+            ]})
+        )
+    );
+    assert_eq!(
+        request(&server, "GET", "/keys", None).1["entries"]
+            .as_array()
+            .unwrap()
+            .len(),
+        3
+    );
+    assert_eq!(
+        request(&server, "GET", "/keys?prefix=nobody", None),
+        (200, json!({"entries": []}))
+    );
+}
+
+#[test]
+fn http_bad_requests() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = start(&temp_dir);
+
+    assert_eq!(request(&server, "PUT", "/keys/key1", Some("value1")).0, 400);
+    assert_eq!(request(&server, "POST", "/keys/key1", None).0, 405);
+    assert_eq!(request(&server, "DELETE", "/keys", None).0, 405);
+    assert_eq!(request(&server, "GET", "/values/key1", None).0, 404);
+    assert_eq!(request(&server, "GET", "/keys/", None).0, 400);
+
+    let mut stream = server.connect();
+    stream.write_all(b"nonsense\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+}
@@ -0,0 +1,227 @@
+use common::ServerProcess;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+
+// A RESP connection to a `kvs-server --protocol resp`.
+struct RespConnection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl RespConnection {
+    fn new(stream: TcpStream) -> RespConnection {
+        RespConnection {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        }
+    }
+
+    // Sends a command as an array of bulk strings, like Redis clients do.
+    fn send(&mut self, args: &[&str]) {
+        let mut command = format!("*{}\r\n", args.len());
+        for arg in args {
+            command.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.writer.write_all(command.as_bytes()).unwrap();
+    }
+
+    // Reads one complete reply and returns its raw bytes.
+    fn read_reply(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        let mut reply = line.clone();
+        let len = line[1..].trim_end();
+        match line.as_bytes()[0] {
+            b'$' if len != "-1" => {
+                let mut bulk = vec![0u8; len.parse::<usize>().unwrap() + 2];
+                self.reader.read_exact(&mut bulk).unwrap();
+                reply.push_str(&String::from_utf8(bulk).unwrap());
+            }
+            b'*' | b'%' => {
+                let items = len.parse::<usize>().unwrap();
+                let items = if line.starts_with('%') {
+                    items * 2
+                } else {
+                    items
+                };
+                for _ in 0..items {
+                    reply.push_str(&self.read_reply());
+                }
+            }
+            _ => {}
+        }
+        reply
+    }
+
+    fn call(&mut self, args: &[&str]) -> String {
+        self.send(args);
+        self.read_reply()
+    }
+}
+
+fn start(temp_dir: &TempDir) -> (ServerProcess, RespConnection) {
+    let server = ServerProcess::start_with_args(temp_dir, &["--protocol", "resp"]);
+    let connection = RespConnection::new(server.connect());
+    (server, connection)
+}
+
+// Clients that keep idle connections open, like Redis client libraries do,
+// don't hold the threads of the pool.
+#[test]
+fn resp_more_idle_clients_than_threads() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server =
+        ServerProcess::start_with_args(&temp_dir, &["--protocol", "resp", "--threads", "2"]);
+    let idle: Vec<_> = (0..3).map(|_| server.connect()).collect();
+
+    let stream = server.connect();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut redis = RespConnection::new(stream);
+    assert_eq!(redis.call(&["PING"]), "+PONG\r\n");
+    drop(idle);
+}
+
+#[test]
+fn resp_get_set_del_exists() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (_server, mut redis) = start(&temp_dir);
+
+    assert_eq!(redis.call(&["PING"]), "+PONG\r\n");
+    assert_eq!(redis.call(&["GET", "key1"]), "$-1\r\n");
+    assert_eq!(redis.call(&["SET", "key1", "value1"]), "+OK\r\n");
+    assert_eq!(redis.call(&["GET", "key1"]), "$6\r\nvalue1\r\n");
+    assert_eq!(redis.call(&["SET", "key2", "value2"]), "+OK\r\n");
+    assert_eq!(redis.call(&["EXISTS", "key1", "key2", "key3"]), ":2\r\n");
+    assert_eq!(redis.call(&["DEL", "key1", "key3"]), ":1\r\n");
+    assert_eq!(redis.call(&["GET", "key1"]), "$-1\r\n");
+    assert_eq!(redis.call(&["EXISTS", "key1"]), ":0\r\n");
+}
+
+#[test]
+fn resp_errors() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (_server, mut redis) = start(&temp_dir);
+
+    assert_eq!(
+        redis.call(&["GET"]),
+        "-ERR wrong number of arguments for 'get' command\r\n"
+    );
+    assert_eq!(
+        redis.call(&["SET", "key1", "value1", "EX", "10"]),
+        "-ERR syntax error\r\n"
+    );
+    assert_eq!(
+        redis.call(&["FLUSHALL"]),
+        "-ERR unknown command 'flushall'\r\n"
+    );
+    // The connection is still usable after an error.
+    assert_eq!(redis.call(&["PING", "hi"]), "$2\r\nhi\r\n");
+}
+
+#[test]
+fn resp3_hello_and_null() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (_server, mut redis) = start(&temp_dir);
+
+    assert_eq!(
+        redis.call(&["HELLO", "4"]),
+        "-NOPROTO unsupported protocol version\r\n"
+    );
+    let hello = redis.call(&["HELLO", "3"]);
+    assert!(hello.starts_with("%7\r\n"));
+    assert!(hello.contains("$5\r\nproto\r\n:3\r\n"));
+    assert_eq!(redis.call(&["GET", "key1"]), "_\r\n");
+
+    let hello = redis.call(&["HELLO", "2"]);
+    assert!(hello.starts_with("*14\r\n"));
+    assert_eq!(redis.call(&["GET", "key1"]), "$-1\r\n");
+}
+
+#[test]
+fn resp_scan() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (server, mut redis) = start(&temp_dir);
+
+    for key in ["apple", "avocado", "banana", "cherry"] {
+        assert_eq!(redis.call(&["SET", key, "fruit"]), "+OK\r\n");
+    }
+
+    assert_eq!(
+        redis.call(&["SCAN", "0", "COUNT", "3"]),
+        "*2\r\n$1\r\n1\r\n*3\r\n$5\r\napple\r\n$7\r\navocado\r\n$6\r\nbanana\r\n"
+    );
+    // Cursors are numbers, and the next page may come over another connection.
+    let mut other = RespConnection::new(server.connect());
+    assert_eq!(
+        other.call(&["SCAN", "1", "COUNT", "3"]),
+        "*2\r\n$1\r\n0\r\n*1\r\n$6\r\ncherry\r\n"
+    );
+    assert_eq!(redis.call(&["SCAN", "99"]), "-ERR invalid cursor\r\n");
+    assert_eq!(redis.call(&["SCAN", ">banana"]), "-ERR invalid cursor\r\n");
+    assert_eq!(
+        redis.call(&["SCAN", "0", "MATCH", "a*", "COUNT", "100"]),
+        "*2\r\n$1\r\n0\r\n*2\r\n$5\r\napple\r\n$7\r\navocado\r\n"
+    );
+    assert_eq!(
+        redis.call(&["SCAN", "0", "MATCH", "[bc]?[^x]*"]),
+        "*2\r\n$1\r\n0\r\n*2\r\n$6\r\nbanana\r\n$6\r\ncherry\r\n"
+    );
+    assert_eq!(
+        redis.call(&["SCAN", "0", "MATCH", "*a*a*a*"]),
+        "*2\r\n$1\r\n0\r\n*1\r\n$6\r\nbanana\r\n"
+    );
+
+    // Removing a key already returned doesn't make the next page skip any.
+    assert_eq!(
+        redis.call(&["SCAN", "0", "COUNT", "2"]),
+        "*2\r\n$1\r\n2\r\n*2\r\n$5\r\napple\r\n$7\r\navocado\r\n"
+    );
+    assert_eq!(redis.call(&["DEL", "apple"]), ":1\r\n");
+    assert_eq!(
+        redis.call(&["SCAN", "2", "COUNT", "2"]),
+        "*2\r\n$1\r\n0\r\n*2\r\n$6\r\nbanana\r\n$6\r\ncherry\r\n"
+    );
+}
+
+// Inline commands and pipelined requests should both work.
+#[test]
+fn resp_inline_and_pipelined() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (_server, mut redis) = start(&temp_dir);
+
+    redis
+        .writer
+        .write_all(b"SET key1 value1\r\nGET key1\r\n")
+        .unwrap();
+    assert_eq!(redis.read_reply(), "+OK\r\n");
+    assert_eq!(redis.read_reply(), "$6\r\nvalue1\r\n");
+
+    redis.send(&["SET", "key2", "value2"]);
+    redis.send(&["GET", "key2"]);
+    redis.send(&["QUIT"]);
+    assert_eq!(redis.read_reply(), "+OK\r\n");
+    assert_eq!(redis.read_reply(), "$6\r\nvalue2\r\n");
+    assert_eq!(redis.read_reply(), "+OK\r\n");
+}
+
+// Malformed input gets a protocol error and the connection is closed.
+#[test]
+fn resp_protocol_error() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (_server, mut redis) = start(&temp_dir);
+
+    redis.writer.write_all(b"*1\r\n+GET\r\n").unwrap();
+    assert_eq!(
+        redis.read_reply(),
+        "-ERR Protocol error: expected '$', got '+'\r\n"
+    );
+    let mut rest = Vec::new();
+    redis.reader.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+}
@@ -1,8 +1,23 @@
+// The CLI tests from upstream pass their arguments as borrowed arrays.
+#![allow(clippy::needless_borrows_for_generic_args)]
+
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::{
+    CompactionPolicy, CustomError, FileStats, GarbageRatioPolicy, KvStore, KvStoreOptions,
+    KvsEngine, MemoryStore, Result, SyncPolicy,
+};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use proptest::prelude::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -65,7 +80,7 @@ fn cli_set() {
 fn cli_get_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
     drop(store);
@@ -95,7 +110,7 @@ fn cli_get_stored() -> Result<()> {
 fn cli_rm_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     drop(store);
 
@@ -118,6 +133,54 @@ fn cli_rm_stored() -> Result<()> {
     Ok(())
 }
 
+// `kvs --engine memory` should persist values between invocations like the default engine.
+#[test]
+fn cli_memory_engine() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "memory", "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "memory", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+}
+
+// The default engine refuses a directory that holds a memory store, instead of taking it over.
+#[test]
+fn cli_memory_directory_is_not_kvs() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "memory", "set", "a", "1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "b", "2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "memory", "get", "a"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("1").trim());
+}
+
 #[test]
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
@@ -179,10 +242,9 @@ fn cli_invalid_subcommand() {
 }
 
 // Should get previously stored value.
-#[test]
-fn get_stored_value() -> Result<()> {
+fn get_stored_value<E: KvsEngine>() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = E::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -192,7 +254,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = E::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -200,10 +262,9 @@ fn get_stored_value() -> Result<()> {
 }
 
 // Should overwrite existent value.
-#[test]
-fn overwrite_value() -> Result<()> {
+fn overwrite_value<E: KvsEngine>() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = E::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -212,7 +273,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = E::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -221,46 +282,263 @@ fn overwrite_value() -> Result<()> {
 }
 
 // Should get `None` when getting a non-existent key.
-#[test]
-fn get_non_existent_value() -> Result<()> {
+fn get_non_existent_value<E: KvsEngine>() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = E::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = E::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
 }
 
-#[test]
-fn remove_non_existent_key() -> Result<()> {
+fn remove_non_existent_key<E: KvsEngine>() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = E::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
 
-#[test]
-fn remove_key() -> Result<()> {
+fn remove_key<E: KvsEngine>() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = E::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
     Ok(())
 }
 
+// Clones used from many threads at once should all see each other's writes.
+fn concurrent_set<E: KvsEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..100 {
+                    store.set(format!("key{}-{}", thread, i), format!("value{}", i))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("writer thread panicked")?;
+    }
+
+    for thread in 0..8 {
+        for i in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}-{}", thread, i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+    assert_eq!(store.keys()?.len(), 800);
+
+    // Open from disk again and check persistent data.
+    store.flush()?;
+    drop(store);
+    let store = E::open(temp_dir.path())?;
+    assert_eq!(store.keys()?.len(), 800);
+    assert_eq!(store.get("key7-99".to_owned())?, Some("value99".to_owned()));
+
+    Ok(())
+}
+
+// Readers running next to a writer always see a complete value.
+fn concurrent_get_during_set<E: KvsEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value0".to_owned())?;
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for i in 1..=200 {
+                store.set("key".to_owned(), format!("value{}", i))?;
+            }
+            Ok(())
+        })
+    };
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..200 {
+                    let value = store.get("key".to_owned())?.expect("key is never removed");
+                    assert!(value.starts_with("value"));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+
+    writer.join().expect("writer thread panicked")?;
+    for reader in readers {
+        reader.join().expect("reader thread panicked")?;
+    }
+    assert_eq!(store.get("key".to_owned())?, Some("value200".to_owned()));
+
+    Ok(())
+}
+
+// Scans return the pairs in a range in key order, either way round and limited.
+fn scan_ranges<E: KvsEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path())?;
+    for key in ["a", "ab", "abc", "ac", "b", "ba"] {
+        store.set(key.to_owned(), format!("{}-value", key))?;
+    }
+    store.remove("ac".to_owned())?;
+    let keys = |scan: E::Scan| -> Result<Vec<String>> {
+        scan.map(|pair| pair.map(|(key, _)| key)).collect()
+    };
+
+    assert_eq!(keys(store.scan(..))?, ["a", "ab", "abc", "b", "ba"]);
+    assert_eq!(
+        keys(store.scan("ab".to_owned().."b".to_owned()))?,
+        ["ab", "abc"]
+    );
+    assert_eq!(
+        keys(store.scan("ab".to_owned()..="b".to_owned()))?,
+        ["ab", "abc", "b"]
+    );
+    assert_eq!(keys(store.scan("c".to_owned()..))?, Vec::<String>::new());
+    assert_eq!(
+        keys(store.scan("b".to_owned().."a".to_owned()))?,
+        Vec::<String>::new()
+    );
+    assert_eq!(keys(store.scan_prefix("ab"))?, ["ab", "abc"]);
+    assert_eq!(keys(store.scan_prefix(""))?.len(), 5);
+
+    let pairs: Vec<_> = store
+        .scan_prefix("a")
+        .rev()
+        .take(2)
+        .collect::<Result<_>>()?;
+    assert_eq!(
+        pairs,
+        [
+            ("abc".to_owned(), "abc-value".to_owned()),
+            ("ab".to_owned(), "ab-value".to_owned())
+        ]
+    );
+
+    // Both ends of one scan meet in the middle
+    let mut scan = store.scan(..);
+    assert_eq!(
+        scan.next().transpose()?.map(|(key, _)| key),
+        Some("a".to_owned())
+    );
+    assert_eq!(
+        scan.next_back().transpose()?.map(|(key, _)| key),
+        Some("ba".to_owned())
+    );
+    assert_eq!(keys(scan)?, ["ab", "abc", "b"]);
+    Ok(())
+}
+
+// Runs the generic engine tests above against every `KvsEngine` implementation.
+macro_rules! engine_tests {
+    ($($module:ident => $engine:ty),* $(,)?) => {
+        $(
+            mod $module {
+                use super::*;
+
+                #[test]
+                fn get_stored_value() -> Result<()> {
+                    super::get_stored_value::<$engine>()
+                }
+
+                #[test]
+                fn overwrite_value() -> Result<()> {
+                    super::overwrite_value::<$engine>()
+                }
+
+                #[test]
+                fn get_non_existent_value() -> Result<()> {
+                    super::get_non_existent_value::<$engine>()
+                }
+
+                #[test]
+                fn remove_non_existent_key() -> Result<()> {
+                    super::remove_non_existent_key::<$engine>()
+                }
+
+                #[test]
+                fn remove_key() -> Result<()> {
+                    super::remove_key::<$engine>()
+                }
+
+                #[test]
+                fn concurrent_set() -> Result<()> {
+                    super::concurrent_set::<$engine>()
+                }
+
+                #[test]
+                fn concurrent_get_during_set() -> Result<()> {
+                    super::concurrent_get_during_set::<$engine>()
+                }
+
+                #[test]
+                fn scan_ranges() -> Result<()> {
+                    super::scan_ranges::<$engine>()
+                }
+            }
+        )*
+    };
+}
+
+engine_tests! {
+    kv_store => KvStore,
+    memory_store => MemoryStore,
+}
+
+// Flushes running next to writes neither lose writes nor get in each other's way.
+#[test]
+fn memory_store_flushes_during_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MemoryStore::open(temp_dir.path())?;
+    let handles: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for key_id in 0..200 {
+                    store.set(format!("key{}-{}", thread_id, key_id), "value".to_owned())?;
+                    if key_id % 10 == 0 {
+                        store.flush()?;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    store.flush()?;
+    assert!(!temp_dir.path().join("memory.json.tmp").exists());
+
+    // Every write made it into the snapshot
+    let reopened = MemoryStore::open(temp_dir.path())?;
+    assert_eq!(reopened.keys()?.len(), 800);
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -290,7 +568,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content.
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -300,3 +578,1280 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Readers must keep finding every value while compaction moves them and deletes old files.
+#[test]
+fn get_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "x".repeat(1024);
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            let done = Arc::clone(&done);
+            thread::spawn(move || -> Result<()> {
+                while !done.load(Ordering::SeqCst) {
+                    for key_id in 0..100 {
+                        assert!(store.get(format!("key{}", key_id))?.is_some());
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+
+    // Overwrite the keys until the log has been compacted a few times.
+    for iter in 0..30 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}{}", iter, value))?;
+        }
+    }
+    done.store(true, Ordering::SeqCst);
+    for reader in readers {
+        reader.join().expect("reader thread panicked")?;
+    }
+
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("29{}", value))
+        );
+    }
+    Ok(())
+}
+
+// Writes made while a background compaction runs must survive it and a reopen.
+#[test]
+fn writes_during_compaction_persist() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "x".repeat(1024);
+
+    for iter in 0..20 {
+        for key_id in 0..200 {
+            store.set(format!("key{}", key_id), format!("{}-{}", iter, value))?;
+        }
+    }
+
+    // Dropping the store waits for a running compaction.
+    drop(store);
+    let written = 20 * 200 * value.len() as u64;
+    let on_disk: u64 = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum();
+    assert!(on_disk < written / 2, "log was never compacted");
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..200 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("19-{}", value))
+        );
+    }
+    Ok(())
+}
+
+// A compaction that crashed before renaming its output leaves a temporary file,
+// which `open` must discard without touching the log.
+#[test]
+fn open_discards_unfinished_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let temp_file = temp_dir.path().join("7.tmp");
+    fs::write(&temp_file, b"half a compaction")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!temp_file.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A crash after the compacted file was renamed into place but before the files it
+// replaced were deleted leaves both on disk; reopening must still see the latest values.
+#[test]
+fn open_after_compaction_crashed_before_delete() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "x".repeat(1024);
+
+    let log_files = |dir: &Path| -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "bin"))
+            .collect();
+        files.sort();
+        files
+    };
+
+    let mut iter = 0;
+    let deleted = loop {
+        assert!(iter < 100, "No compaction detected");
+        // Keep a copy of every log file, to bring back the ones compaction deletes.
+        for path in log_files(temp_dir.path()) {
+            fs::copy(&path, backup_dir.path().join(path.file_name().unwrap()))?;
+        }
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}-{}", iter, value))?;
+        }
+        iter += 1;
+
+        let current = log_files(temp_dir.path());
+        let deleted: Vec<PathBuf> = log_files(backup_dir.path())
+            .into_iter()
+            .filter(|path| !current.contains(&temp_dir.path().join(path.file_name().unwrap())))
+            .collect();
+        if !deleted.is_empty() {
+            break deleted;
+        }
+    };
+    drop(store);
+
+    for path in deleted {
+        fs::copy(&path, temp_dir.path().join(path.file_name().unwrap()))?;
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}-{}", iter - 1, value))
+        );
+    }
+    Ok(())
+}
+
+// Flip one byte of the value stored at the start of the log file.
+fn corrupt_first_record(dir: &Path) {
+    let path = dir.join("0.bin");
+    let mut bytes = fs::read(&path).expect("unable to read log file");
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    fs::write(&path, bytes).expect("unable to write log file");
+}
+
+// A record that fails its checksum is reported with its location when replaying the log.
+#[test]
+fn open_detects_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    corrupt_first_record(temp_dir.path());
+    match KvStore::open(temp_dir.path()) {
+        Err(CustomError::Corrupted { file: 0, offset: 8 }) => Ok(()),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption went unnoticed"),
+    }
+}
+
+// A record corrupted after the log was replayed is detected when it is read.
+#[test]
+fn get_detects_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    corrupt_first_record(temp_dir.path());
+    match store.get("key1".to_owned()) {
+        Err(CustomError::Corrupted { file: 0, offset: 8 }) => Ok(()),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(value) => panic!("corruption went unnoticed, got {:?}", value),
+    }
+}
+
+// A record cut short by a crash is dropped on open, and later writes are not lost behind it.
+#[test]
+fn open_truncates_incomplete_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // Cut the last record in half.
+    let path = temp_dir.path().join("0.bin");
+    let len = fs::metadata(&path)?.len();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&path)?
+        .set_len(len - 5)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    let discarded = store.discarded_bytes();
+    assert!(discarded > 0 && discarded < len);
+    assert_eq!(fs::metadata(&path)?.len(), len - 5 - discarded);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.discarded_bytes(), 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+/// The offsets of the records in a log file: after the log header, records are framed by
+/// their length, its checksum and the checksum of the header
+fn record_offsets(bytes: &[u8]) -> Vec<usize> {
+    let mut offsets = Vec::new();
+    let mut pos = 8;
+    while pos + 12 <= bytes.len() {
+        offsets.push(pos);
+        pos += 12 + u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize;
+    }
+    offsets
+}
+
+// A flipped bit in a record length must not pass for a record cut short by a crash,
+// which would discard every record after it.
+#[test]
+fn open_refuses_damaged_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    drop(store);
+
+    // Make the length of a record in the middle run past the end of the file.
+    let path = temp_dir.path().join("0.bin");
+    let mut bytes = fs::read(&path)?;
+    let offset = record_offsets(&bytes)[50];
+    bytes[offset + 3] ^= 0x01;
+    fs::write(&path, &bytes)?;
+
+    let result = KvStore::open(temp_dir.path());
+    assert!(
+        matches!(result, Err(CustomError::Corrupted { file: 0, offset: o }) if o == offset as u64)
+    );
+    assert_eq!(fs::read(&path)?, bytes);
+    Ok(())
+}
+
+// Only the active file can end with a record cut short; a sealed file that does lost data.
+#[test]
+fn open_refuses_short_sealed_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    fill_sealed_file(&store)?;
+    drop(store);
+
+    let path = temp_dir.path().join("0.bin");
+    let len = fs::metadata(&path)?.len();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&path)?
+        .set_len(len - 5)?;
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(CustomError::Corrupted { file: 0, .. })
+    ));
+    assert_eq!(fs::metadata(&path)?.len(), len - 5);
+    Ok(())
+}
+
+// Simulates a crash of the machine, which loses whatever the store didn't sync: the log of a
+// store written in one session is cut right after its `synced_seq`-th record.
+fn lose_unsynced_writes(dir: &Path, synced_seq: u64) {
+    let path = dir.join("0.bin");
+    let bytes = fs::read(&path).expect("unable to read log file");
+    let len = record_offsets(&bytes)
+        .get(synced_seq as usize)
+        .copied()
+        .unwrap_or(bytes.len());
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .and_then(|file| file.set_len(len as u64))
+        .expect("unable to truncate log file");
+}
+
+// Every policy keeps the writes it synced through a crash of the machine, and `flush` syncs
+// whatever the policy.
+#[test]
+fn sync_policies_survive_crash() -> Result<()> {
+    // Each policy with how many of 10 writes it has synced once they all returned
+    let policies = [
+        (SyncPolicy::Never, 0),
+        (SyncPolicy::Always, 10),
+        (SyncPolicy::EveryN(3), 9),
+        (SyncPolicy::Interval(Duration::ZERO), 10),
+        (SyncPolicy::Interval(Duration::from_secs(3600)), 0),
+        (SyncPolicy::GroupCommit, 10),
+    ];
+    for (policy, synced) in policies {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().sync_policy(policy);
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        for i in 0..10 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        assert_eq!(store.synced_seq(), synced, "{:?}", policy);
+        drop(store);
+
+        lose_unsynced_writes(temp_dir.path(), synced);
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        assert_eq!(store.keys()?.len() as u64, synced, "{:?}", policy);
+        for i in 0..synced {
+            assert_eq!(
+                store.get(format!("key{}", i))?,
+                Some(format!("value{}", i)),
+                "{:?}",
+                policy
+            );
+        }
+
+        store.set("key10".to_owned(), "value10".to_owned())?;
+        store.flush()?;
+        assert_eq!(store.synced_seq(), 1, "{:?}", policy);
+    }
+    Ok(())
+}
+
+// Writers that arrive while a group commit is syncing share the next sync.
+#[test]
+fn group_commit_batches_writers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().sync_policy(SyncPolicy::GroupCommit);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..50 {
+                    store.set(format!("key{}-{}", thread, i), format!("value{}", i))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("writer thread panicked")?;
+    }
+
+    assert_eq!(store.synced_seq(), 400);
+    assert!(
+        store.syncs() < 400,
+        "{} syncs for 400 writes",
+        store.syncs()
+    );
+    Ok(())
+}
+
+// Fill the store past the size of one log file, so the first file is sealed.
+fn fill_sealed_file(store: &KvStore) -> Result<()> {
+    let value = "x".repeat(1024);
+    for key_id in 0..1100 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    Ok(())
+}
+
+// `open` indexes sealed files from their hint files, without reading the values.
+#[test]
+fn open_uses_hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    fill_sealed_file(&store)?;
+    drop(store);
+    assert!(temp_dir.path().join("0.hint").exists());
+
+    // Damage the first value; only reading it can notice.
+    let path = temp_dir.path().join("0.bin");
+    let mut bytes = fs::read(&path)?;
+    bytes[20] ^= 0xff;
+    fs::write(&path, bytes)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys()?.len(), 1100);
+    assert_eq!(store.get("key1099".to_owned())?, Some("x".repeat(1024)));
+    assert!(matches!(
+        store.get("key0".to_owned()),
+        Err(CustomError::Corrupted { file: 0, offset: 8 })
+    ));
+    Ok(())
+}
+
+// A damaged hint file is ignored and rebuilt from its log file, and a hint file
+// without a log file is removed.
+#[test]
+fn open_rebuilds_bad_hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    fill_sealed_file(&store)?;
+    drop(store);
+
+    let hint_path = temp_dir.path().join("0.hint");
+    let hint_len = fs::metadata(&hint_path)?.len();
+    fs::write(&hint_path, b"not a hint file")?;
+    let orphan_path = temp_dir.path().join("42.hint");
+    fs::write(&orphan_path, b"")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys()?.len(), 1100);
+    assert_eq!(store.get("key0".to_owned())?, Some("x".repeat(1024)));
+    assert_eq!(fs::metadata(&hint_path)?.len(), hint_len);
+    assert!(!orphan_path.exists());
+    Ok(())
+}
+
+// Log files the manifest does not list are leftovers, so open ignores and deletes them
+#[test]
+fn open_ignores_unlisted_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let leftover = temp_dir.path().join("99.bin");
+    fs::write(&leftover, b"not a log file")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!leftover.exists());
+    Ok(())
+}
+
+#[test]
+fn open_refuses_incompatible_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // A kvs directory is not a memory store
+    assert!(matches!(
+        MemoryStore::open(temp_dir.path()),
+        Err(CustomError::IncompatibleStore(_))
+    ));
+
+    // Nor can this build read a format version it doesn't know
+    let manifest_path = temp_dir.path().join("MANIFEST");
+    let manifest = fs::read_to_string(&manifest_path)?;
+    fs::write(
+        &manifest_path,
+        manifest.replace("\"format_version\": 2", "\"format_version\": 99"),
+    )?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(CustomError::IncompatibleStore(_))
+    ));
+    Ok(())
+}
+
+/// A record of the log files written by older format versions
+#[derive(Serialize)]
+enum LegacyTransaction<'a> {
+    Set(&'a str, &'a str),
+    Remove(&'a str),
+}
+
+/// Write a log file the way format version 1 did: bare bincode records back to back
+fn write_legacy_log(dir: &Path, file_index: u32, records: &[LegacyTransaction]) {
+    let mut bytes = Vec::new();
+    for record in records {
+        let payload = bincode::serialize(record).expect("unable to serialize record");
+        bytes.extend_from_slice(&payload);
+    }
+    fs::write(dir.join(format!("{}.bin", file_index)), bytes).expect("unable to write log file");
+}
+
+fn write_legacy_store(dir: &Path) {
+    use LegacyTransaction::{Remove, Set};
+    write_legacy_log(dir, 0, &[Set("key1", "value1"), Set("key2", "value2")]);
+    write_legacy_log(dir, 1, &[Remove("key1"), Set("key2", "value3")]);
+}
+
+#[test]
+fn upgrade_format_1_in_place() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_legacy_store(temp_dir.path());
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(CustomError::IncompatibleStore(_))
+    ));
+    assert_eq!(KvStore::upgrade(temp_dir.path())?, 1);
+    assert!(!temp_dir.path().join("0.bin").exists());
+    assert!(!temp_dir.path().join("1.bin").exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    // Upgrading a current store changes nothing
+    assert_eq!(KvStore::upgrade(temp_dir.path())?, KvStore::FORMAT_VERSION);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn upgrade_into_new_directory() -> Result<()> {
+    let old_dir = TempDir::new().expect("unable to create temporary working directory");
+    let new_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_legacy_store(old_dir.path());
+    let old_log = fs::read(old_dir.path().join("0.bin"))?;
+
+    assert_eq!(KvStore::upgrade_into(old_dir.path(), new_dir.path())?, 1);
+    assert_eq!(fs::read(old_dir.path().join("0.bin"))?, old_log);
+    let store = KvStore::open(new_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    // The target has to be empty
+    assert!(matches!(
+        KvStore::upgrade_into(old_dir.path(), new_dir.path()),
+        Err(CustomError::IncompatibleStore(_))
+    ));
+    Ok(())
+}
+
+// `kvs upgrade` should migrate the store in the current directory.
+#[test]
+fn cli_upgrade() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_legacy_store(temp_dir.path());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["upgrade"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Upgraded from format version 1 to 2").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value3").trim());
+}
+
+#[test]
+fn open_locks_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(CustomError::Locked(_))
+    ));
+    assert!(matches!(
+        KvStore::upgrade(temp_dir.path()),
+        Err(CustomError::Locked(_))
+    ));
+
+    // Clones share the lock
+    let clone = store.clone();
+    clone.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    drop(clone);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// `kvs set` should fail while another process has the store open.
+#[test]
+fn cli_set_locked() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+/// The names and contents of every file in a directory
+fn dir_contents(dir: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+    let mut contents: Vec<_> = fs::read_dir(dir)
+        .expect("unable to read directory")
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            let bytes = fs::read(&path).expect("unable to read file");
+            (path, bytes)
+        })
+        .collect();
+    contents.sort();
+    contents
+}
+
+#[test]
+fn open_read_only_writes_nothing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    // A new store isn't created
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    drop(store);
+    assert!(dir_contents(temp_dir.path()).is_empty());
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    // Leave things behind that a writable open would clean up
+    let path = temp_dir.path().join("0.bin");
+    let len = fs::metadata(&path)?.len();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&path)?
+        .set_len(len - 5)?;
+    fs::write(temp_dir.path().join("7.tmp"), b"unfinished compaction")?;
+    let before = dir_contents(temp_dir.path());
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(matches!(
+        store.set("key3".to_owned(), "value3".to_owned()),
+        Err(CustomError::ReadOnly)
+    ));
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(CustomError::ReadOnly)
+    ));
+    store.flush()?;
+    drop(store);
+    assert_eq!(dir_contents(temp_dir.path()), before);
+    Ok(())
+}
+
+#[test]
+fn open_read_only_next_to_writer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // The writer goes on, the reader keeps what it saw when it was opened
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(reader.get("key2".to_owned())?, None);
+    assert_eq!(
+        KvStore::open_read_only(temp_dir.path())?.get("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
+    Ok(())
+}
+
+// `kvs get` should work while another process has the store open; writes should not.
+#[test]
+fn cli_get_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--read-only", "set", "key1", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--read-only", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    Ok(())
+}
+
+// Tombstones go to the active file like values, so deleting keys doesn't add log files.
+#[test]
+fn removes_share_log_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for round in 0..3 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("value{}", round))?;
+        }
+        for key_id in 0..1000 {
+            store.remove(format!("key{}", key_id))?;
+        }
+    }
+    drop(store);
+
+    let log_files = fs::read_dir(temp_dir.path())?
+        .filter(|entry| {
+            let path = entry.as_ref().unwrap().path();
+            path.extension().is_some_and(|ext| ext == "bin")
+        })
+        .count();
+    assert!(log_files <= 2, "{} log files", log_files);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys()?, Vec::<String>::new());
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+enum Op {
+    Set(u8, u32),
+    Remove(u8),
+    Reopen,
+}
+
+fn op_strategy() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        6 => (0..8u8, any::<u32>()).prop_map(|(key, value)| Op::Set(key, value)),
+        3 => (0..8u8).prop_map(Op::Remove),
+        1 => Just(Op::Reopen),
+    ]
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(16))]
+
+    // Values are large enough that log files fill up and get compacted every few dozen
+    // writes, so tombstones keep getting compacted away from the values they shadow.
+    #[test]
+    fn removed_keys_stay_removed(ops in proptest::collection::vec(op_strategy(), 1..300)) {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let filler = "x".repeat(32 * 1024);
+        let mut store = KvStore::open(temp_dir.path())?;
+        let mut model = BTreeMap::new();
+
+        let check = |store: &KvStore, model: &BTreeMap<u8, u32>| -> Result<()> {
+            for key in 0..8u8 {
+                let expected = model.get(&key).map(|value| format!("{}-{}", value, filler));
+                assert_eq!(store.get(format!("key{}", key))?, expected, "key{}", key);
+            }
+            Ok(())
+        };
+        for op in ops {
+            match op {
+                Op::Set(key, value) => {
+                    store.set(format!("key{}", key), format!("{}-{}", value, filler))?;
+                    model.insert(key, value);
+                }
+                Op::Remove(key) => {
+                    let removed = store.remove(format!("key{}", key));
+                    match model.remove(&key) {
+                        Some(_) => removed?,
+                        None => assert!(matches!(removed, Err(CustomError::KeyNotFound))),
+                    }
+                }
+                Op::Reopen => {
+                    drop(store);
+                    store = KvStore::open(temp_dir.path())?;
+                    check(&store, &model)?;
+                }
+            }
+        }
+        drop(store);
+        check(&KvStore::open(temp_dir.path())?, &model)?;
+    }
+}
+
+/// The number of log files in a directory and their total size
+fn log_files_size(dir: &Path) -> (usize, u64) {
+    fs::read_dir(dir)
+        .expect("unable to read directory")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "bin"))
+        .fold((0, 0), |(count, size), path| {
+            (count + 1, size + fs::metadata(path).unwrap().len())
+        })
+}
+
+#[test]
+fn garbage_ratio_policy_selects_files() {
+    let stats = |total_bytes, stale_bytes| FileStats {
+        total_bytes,
+        stale_bytes,
+    };
+    let files = BTreeMap::from([(0, stats(100, 80)), (1, stats(100, 10)), (2, stats(100, 0))]);
+
+    let policy = GarbageRatioPolicy::default();
+    assert_eq!(policy.select(&files, 300), vec![0]);
+
+    // Over the disk budget, every file with garbage goes
+    let policy = GarbageRatioPolicy {
+        max_disk_usage: Some(200),
+        ..GarbageRatioPolicy::default()
+    };
+    assert_eq!(policy.select(&files, 300), vec![0, 1]);
+    assert_eq!(policy.select(&files, 200), vec![0]);
+}
+
+#[derive(Debug)]
+struct NeverCompact {
+    asked: Arc<AtomicUsize>,
+}
+
+impl CompactionPolicy for NeverCompact {
+    fn select(&self, _files: &BTreeMap<u32, FileStats>, _disk_usage: u64) -> Vec<u32> {
+        self.asked.fetch_add(1, Ordering::SeqCst);
+        Vec::new()
+    }
+}
+
+/// Compacts every sealed file with garbage except file 0, and remembers the files it saw
+#[derive(Debug, Clone, Default)]
+struct SkipFirstFile {
+    seen: Arc<Mutex<Vec<BTreeMap<u32, FileStats>>>>,
+}
+
+impl CompactionPolicy for SkipFirstFile {
+    fn select(&self, files: &BTreeMap<u32, FileStats>, _disk_usage: u64) -> Vec<u32> {
+        self.seen.lock().unwrap().push(files.clone());
+        files
+            .iter()
+            .filter(|(&file_index, stats)| file_index != 0 && stats.stale_bytes > 0)
+            .map(|(&file_index, _)| file_index)
+            .collect()
+    }
+}
+
+// The tombstones a compaction keeps aren't garbage, or its output would be compacted again
+// at every rotation, before and after reopening.
+#[test]
+fn kept_tombstones_are_not_garbage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let policy = SkipFirstFile::default();
+    let options = KvStoreOptions::new()
+        .max_file_size(4 * 1024)
+        .compaction_policy(policy.clone());
+    let value = "x".repeat(1024);
+    let output_stats = |policy: &SkipFirstFile| policy.seen.lock().unwrap().last().unwrap()[&2];
+
+    // File 0 keeps the values, so compacting file 1 into file 2 keeps their tombstones.
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for i in 0..4 {
+        store.set(format!("key{}", i), value.clone())?;
+    }
+    for i in 0..4 {
+        store.remove(format!("key{}", i))?;
+    }
+    for i in 0..8 {
+        store.set(format!("fill{}", i), value.clone())?;
+    }
+    let started = Instant::now();
+    while temp_dir.path().join("1.bin").exists() {
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "compaction hangs"
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+    store.set("fill8".to_owned(), value.clone())?;
+    assert_eq!(output_stats(&policy).stale_bytes, 0);
+    drop(store);
+
+    let policy = SkipFirstFile::default();
+    let store = KvStore::open_with(temp_dir.path(), options.compaction_policy(policy.clone()))?;
+    for i in 9..13 {
+        store.set(format!("fill{}", i), value.clone())?;
+    }
+    assert_eq!(output_stats(&policy).stale_bytes, 0);
+    for i in 0..4 {
+        assert_eq!(store.get(format!("key{}", i))?, None);
+    }
+    Ok(())
+}
+
+#[test]
+fn custom_compaction_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let asked = Arc::new(AtomicUsize::new(0));
+    let options = KvStoreOptions::new()
+        .max_file_size(4 * 1024)
+        .compaction_policy(NeverCompact {
+            asked: Arc::clone(&asked),
+        });
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let value = "x".repeat(1024);
+    for i in 0..200 {
+        store.set(format!("key{}", i % 10), format!("{}-{}", i, value))?;
+    }
+    drop(store);
+
+    // Every file was sealed and kept
+    let (files, _) = log_files_size(temp_dir.path());
+    assert!(files >= 40, "{} log files", files);
+    assert_eq!(asked.load(Ordering::SeqCst), files - 1);
+    Ok(())
+}
+
+#[test]
+fn max_disk_usage_triggers_compaction() -> Result<()> {
+    let value = "x".repeat(1024);
+    let fill = |options: KvStoreOptions| -> Result<u64> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        for i in 0..1000 {
+            store.set(format!("key{}", i % 10), format!("{}-{}", i, value))?;
+        }
+        drop(store);
+        Ok(log_files_size(temp_dir.path()).1)
+    };
+
+    // A garbage ratio above 1 is never reached, so only the disk budget compacts.
+    let options = KvStoreOptions::new()
+        .max_file_size(4 * 1024)
+        .garbage_ratio(2.0);
+    assert!(fill(options.clone())? > 1000 * 1024);
+    let size = fill(options.max_disk_usage(64 * 1024))?;
+    assert!(size < 256 * 1024, "{} bytes of log files", size);
+    Ok(())
+}
+
+// A manual compaction rewrites every file with garbage, the active one included.
+#[test]
+fn compact_reclaims_space() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = "x".repeat(1024);
+    // The default policy never kicks in
+    let options = KvStoreOptions::new().garbage_ratio(2.0);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for i in 0..200 {
+        store.set(format!("key{}", i), format!("{}-{}", i, value))?;
+    }
+    for i in 0..190 {
+        store.remove(format!("key{}", i))?;
+    }
+    let size_before = store_files_size(temp_dir.path());
+
+    let report = store.compact()?;
+    assert!(report.files_rewritten > 0);
+    assert_eq!(
+        report.bytes_reclaimed,
+        size_before - store_files_size(temp_dir.path())
+    );
+    let (files, size_after) = log_files_size(temp_dir.path());
+    assert!(size_after < 20 * 1024, "{} bytes of log files", size_after);
+    assert_eq!(files, 1);
+    assert_eq!(store.get("key189".to_owned())?, None);
+    assert_eq!(
+        store.get("key190".to_owned())?,
+        Some(format!("190-{}", value))
+    );
+
+    // Nothing is left to do
+    assert_eq!(store.compact()?.files_rewritten, 0);
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.keys()?.len(), 10);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(
+        store.get("key199".to_owned())?,
+        Some(format!("199-{}", value))
+    );
+    Ok(())
+}
+
+// Clones read the same open log files at once, and a compaction retiring one of them
+// closes and deletes it once the last read is done.
+#[test]
+fn concurrent_reads_across_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..200 {
+                    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    assert_eq!(store.compact()?.files_rewritten, 1);
+    for reader in readers {
+        reader.join().expect("reader thread panicked")?;
+    }
+
+    assert!(!temp_dir.path().join("0.bin").exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// The log files and their hint files
+fn store_files_size(dir: &Path) -> u64 {
+    fs::read_dir(dir)
+        .expect("unable to read directory")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|ext| ext == "bin" || ext == "hint")
+        })
+        .map(|path| fs::metadata(path).unwrap().len())
+        .sum()
+}
+
+// Files a snapshot reads from aren't freed by a compaction, so they aren't reported either.
+#[test]
+fn compact_reports_freed_space_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set("key".to_owned(), format!("{}-{}", i, "x".repeat(1024)))?;
+    }
+    let snapshot = store.snapshot();
+    let size_before = store_files_size(temp_dir.path());
+
+    let report = store.compact()?;
+    assert_eq!(report.files_rewritten, 1);
+    assert_eq!(report.bytes_reclaimed, 0);
+    assert!(store_files_size(temp_dir.path()) > size_before);
+
+    drop(snapshot);
+    assert!(store_files_size(temp_dir.path()) < 4 * 1024);
+    Ok(())
+}
+
+// A failed compaction leaves the store as it was, however often it is retried.
+#[test]
+fn compact_retried_after_failure() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    fill_sealed_file(&store)?;
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    // Damage the stale value of key0, which only compaction reads.
+    let path = temp_dir.path().join("0.bin");
+    let mut bytes = fs::read(&path)?;
+    bytes[20] ^= 0xff;
+    fs::write(&path, bytes)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    for _ in 0..2 {
+        assert!(matches!(
+            store.compact(),
+            Err(CustomError::Corrupted { file: 0, offset: 8 })
+        ));
+    }
+    drop(store);
+
+    // Every sealed file the manifest lists exists
+    let manifest: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(temp_dir.path().join("MANIFEST"))?)?;
+    let active = manifest["active"].as_u64().unwrap();
+    for file_index in manifest["files"].as_array().unwrap() {
+        let file_index = file_index.as_u64().unwrap();
+        assert!(
+            file_index == active || temp_dir.path().join(format!("{}.bin", file_index)).exists(),
+            "{} is listed but missing",
+            file_index
+        );
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("x".repeat(1024)));
+    Ok(())
+}
+
+// A sealed file the manifest lists without it ever having been written holds no records.
+#[test]
+fn open_skips_missing_sealed_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let manifest_path = temp_dir.path().join("MANIFEST");
+    let manifest: serde_json::Value = serde_json::from_str(&fs::read_to_string(&manifest_path)?)?;
+    assert_eq!(manifest["active"], 0);
+    fs::write(
+        &manifest_path,
+        serde_json::to_string(&serde_json::json!({
+            "format_version": manifest["format_version"],
+            "engine": "kvs",
+            "files": [0, 1, 2],
+            "active": 2,
+        }))?,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn compact_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(temp_dir.path())?;
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert!(matches!(store.compact(), Err(CustomError::ReadOnly)));
+    Ok(())
+}
+
+#[test]
+fn cli_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Compacted 1 files"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value2").trim());
+    Ok(())
+}
+
+// A scan reads the store as it was when it started, even across writes and compactions.
+#[test]
+fn scan_sees_one_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let scan = store.scan_prefix("key");
+    store.set("key0".to_owned(), "overwritten".to_owned())?;
+    store.remove("key5".to_owned())?;
+    store.set("key10".to_owned(), "new".to_owned())?;
+    store.compact()?;
+
+    let pairs: Vec<_> = scan.collect::<Result<_>>()?;
+    let expected: Vec<_> = (0..10)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    assert_eq!(pairs, expected);
+    assert_eq!(store.scan_prefix("key").count(), 10);
+    Ok(())
+}
+
+#[test]
+fn cli_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in ["a", "b1", "b2", "b3", "c"] {
+        store.set(key.to_owned(), format!("{}-value", key))?;
+    }
+    drop(store);
+
+    let scan = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .arg("scan")
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+    };
+    scan(&[]).stdout("a\ta-value\nb1\tb1-value\nb2\tb2-value\nb3\tb3-value\nc\tc-value\n");
+    scan(&["--prefix", "b"]).stdout("b1\tb1-value\nb2\tb2-value\nb3\tb3-value\n");
+    scan(&["--from", "b2", "--to", "c"]).stdout("b2\tb2-value\nb3\tb3-value\n");
+    scan(&["--reverse", "--limit", "2"]).stdout("c\tc-value\nb3\tb3-value\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan", "--prefix", "b", "--from", "a"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Ok(())
+}
+
+// A snapshot reads the store as of its sequence number, and keeps the files it needs
+// through compactions until it is dropped.
+#[test]
+fn snapshot_is_pinned() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_file_size(1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let value = "x".repeat(100);
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("{}-{}", i, value))?;
+    }
+
+    let snapshot = store.snapshot();
+    assert_eq!(snapshot.seq(), 50);
+    for i in 0..50 {
+        store.set(format!("key{}", i), "overwritten".to_owned())?;
+    }
+    store.remove("key7".to_owned())?;
+    store.set("key50".to_owned(), "new".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.snapshot().seq(), 102);
+
+    assert_eq!(
+        snapshot.get("key7".to_owned())?,
+        Some(format!("7-{}", value))
+    );
+    assert_eq!(snapshot.get("key50".to_owned())?, None);
+    assert_eq!(snapshot.keys()?.len(), 50);
+    let pairs: Vec<_> = snapshot.scan_prefix("key4").collect::<Result<_>>()?;
+    assert_eq!(pairs.len(), 11);
+    assert!(pairs.iter().all(|(_, value)| value.ends_with('x')));
+    assert_eq!(store.get("key7".to_owned())?, None);
+    assert_eq!(
+        store.get("key8".to_owned())?,
+        Some("overwritten".to_owned())
+    );
+
+    // The compacted files go once the snapshot is gone
+    let (files, _) = log_files_size(temp_dir.path());
+    drop(snapshot);
+    let (files_after, _) = log_files_size(temp_dir.path());
+    assert!(files_after < files, "{} files, then {}", files, files_after);
+    Ok(())
+}
@@ -0,0 +1,60 @@
+use kvs::{NaiveThreadPool, RayonThreadPool, Result, SharedQueueThreadPool, ThreadPool};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier};
+
+// Runs `jobs` jobs on the pool and waits until every one has finished.
+fn spawn_counter<P: ThreadPool>(pool: P, jobs: usize) -> Result<()> {
+    const ADDS_PER_JOB: usize = 100;
+    let counter = Arc::new(AtomicUsize::new(0));
+    let barrier = Arc::new(Barrier::new(jobs + 1));
+
+    for _ in 0..jobs {
+        let counter = Arc::clone(&counter);
+        let barrier = Arc::clone(&barrier);
+        pool.spawn(move || {
+            for _ in 0..ADDS_PER_JOB {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+            barrier.wait();
+        });
+    }
+
+    barrier.wait();
+    assert_eq!(counter.load(Ordering::SeqCst), jobs * ADDS_PER_JOB);
+    Ok(())
+}
+
+// Panics in `panics` jobs, then checks that the pool still runs all of its threads.
+fn spawn_panic_task<P: ThreadPool>(threads: usize, panics: usize) -> Result<()> {
+    let pool = P::new(threads as u32)?;
+    for _ in 0..panics {
+        pool.spawn(|| panic!("intentional panic in a job"));
+    }
+    // Only completes if `threads` workers are alive to reach the barrier together.
+    spawn_counter(pool, threads)
+}
+
+#[test]
+fn naive_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(NaiveThreadPool::new(4)?, 4)
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(SharedQueueThreadPool::new(4)?, 4)
+}
+
+#[test]
+fn rayon_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(RayonThreadPool::new(4)?, 4)
+}
+
+#[test]
+fn shared_queue_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<SharedQueueThreadPool>(4, 8)
+}
+
+#[test]
+fn rayon_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<RayonThreadPool>(4, 8)
+}